@@ -0,0 +1,512 @@
+use crate::error::SelectorError;
+use crate::server::ServerConfig;
+use crate::types::{HistoryRequest, Request, SubmitRequest};
+use serde::Deserialize;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+
+/// One `[[acl]]` entry of the config: what its users and groups may do.
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct AclRule {
+    #[serde(default)]
+    pub users: Vec<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Nodes or node groups, `*` for all of them.
+    #[serde(default)]
+    pub nodes: Vec<String>,
+    /// Patterns of the commands allowed on `nodes`, see `command_matches`.
+    #[serde(default)]
+    pub commands: Vec<String>,
+    /// Whether files may be uploaded to and downloaded from `nodes`.
+    #[serde(default)]
+    pub transfer: bool,
+    /// Whether the server may be administered, e.g. reloaded or its status
+    /// seen.
+    #[serde(default)]
+    pub admin: bool,
+}
+
+/// The local user on the other end of the control socket.
+#[derive(Debug)]
+pub struct Peer {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: i32,
+    pub user: Option<String>,
+    /// Names of the primary and supplementary groups of `user`.
+    pub groups: Vec<String>,
+}
+
+impl Peer {
+    /// Reads the credentials of the process connected to `stream`.
+    pub fn from_stream(stream: &UnixStream) -> io::Result<Peer> {
+        let mut cred: libc::ucred = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let user = user_name(cred.uid);
+        let gids = match &user {
+            Some(user) => group_list(user, cred.gid),
+            None => vec![cred.gid],
+        };
+
+        Ok(Peer {
+            uid: cred.uid,
+            gid: cred.gid,
+            pid: cred.pid,
+            user,
+            groups: gids.into_iter().filter_map(group_name).collect(),
+        })
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.user {
+            Some(user) => write!(f, "{} (uid {}, pid {})", user, self.uid, self.pid),
+            None => write!(f, "uid {} (pid {})", self.uid, self.pid),
+        }
+    }
+}
+
+impl AclRule {
+    fn applies_to(&self, peer: &Peer) -> bool {
+        let user = peer
+            .user
+            .as_ref()
+            .is_some_and(|user| self.users.contains(user));
+        user || peer.groups.iter().any(|group| self.groups.contains(group))
+    }
+
+    fn covers(&self, config: &ServerConfig, node_name: &String) -> bool {
+        config.resolve_nodes(&self.nodes).contains(&node_name)
+    }
+
+    fn allows_command(&self, command: &str) -> bool {
+        self.commands
+            .iter()
+            .any(|pattern| command_matches(pattern, command))
+    }
+}
+
+/// Characters the remote shell gives a meaning to, which would let a command
+/// run more than what its pattern allows: separators, redirections and
+/// substitutions, but also globs, braces, tildes, quotes and escapes, which
+/// it expands into other words than the ones matched.
+const SHELL_METACHARACTERS: &[char] = &[
+    ';', '|', '&', '$', '`', '(', ')', '<', '>', '\n', '*', '?', '[', '{', '~', '\'', '"', '\\',
+];
+
+/// Checks `request` against the ACL of `config`. Without any rule, or for
+/// root, everything is allowed. Otherwise each node of the request must be
+/// covered by a rule of the peer allowing what's asked, and the server
+/// status and reloads are for admins.
+pub fn authorize(config: &ServerConfig, peer: &Peer, request: &Request) -> Result<(), String> {
+    if config.acl.is_empty() || peer.uid == 0 {
+        return Ok(());
+    }
+
+    let rules: Vec<&AclRule> = config
+        .acl
+        .iter()
+        .filter(|rule| rule.applies_to(peer))
+        .collect();
+    let admin = rules.iter().any(|rule| rule.admin);
+    let check_nodes = |names: &[String], action: &str, allows: &dyn Fn(&AclRule) -> bool| {
+        // Names of no node are denied too, whether they exist mustn't show.
+        let mut denied = unknown_terms(config, names);
+        denied.extend(
+            config
+                .resolve_nodes(names)
+                .into_iter()
+                .filter(|node_name| {
+                    !rules
+                        .iter()
+                        .any(|rule| allows(rule) && rule.covers(config, node_name))
+                })
+                .cloned(),
+        );
+        if denied.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "{} may not {} on: {}",
+                peer,
+                action,
+                denied.join(", ")
+            ))
+        }
+    };
+
+    match request {
+        // Whose jobs and history may be seen is up to `may_access`, reruns
+        // are authorized as the command requests they become.
+        Request::Hello(_) | Request::Job(_) | Request::Rerun(_) => Ok(()),
+        Request::History(HistoryRequest::List(filter)) if !admin => {
+            let unknown = unknown_terms(config, &filter.nodes);
+            if unknown.is_empty() {
+                Ok(())
+            } else {
+                Err(format!(
+                    "{} may not look up the history of: {}",
+                    peer,
+                    unknown.join(", ")
+                ))
+            }
+        }
+        Request::History(_) => Ok(()),
+        Request::Cmd(cmd_request) | Request::Submit(SubmitRequest { cmd: cmd_request }) => {
+            check_nodes(
+                &cmd_request.nodes,
+                &format!("run '{}'", cmd_request.command),
+                &|rule| rule.allows_command(&cmd_request.command),
+            )
+        }
+        Request::Upload(upload_request) => {
+            check_nodes(&upload_request.nodes, "upload files", &|rule| rule.transfer)
+        }
+        Request::Download(download_request) => {
+            check_nodes(&download_request.nodes, "download files", &|rule| {
+                rule.transfer
+            })
+        }
+        // The pool status lists the nodes sessions were opened to.
+        Request::Status(_) | Request::Reload(_) if admin => Ok(()),
+        Request::Status(_) => Err(format!("{} may not see the server status", peer)),
+        Request::Reload(_) => Err(format!("{} may not reload the configuration", peer)),
+    }
+}
+
+/// Terms of `selectors` selecting no node, invalid selectors being left to
+/// the request validation.
+fn unknown_terms(config: &ServerConfig, selectors: &[String]) -> Vec<String> {
+    match config.select_nodes(selectors) {
+        Err(SelectorError::Unknown(terms)) => terms,
+        _ => Vec::new(),
+    }
+}
+
+/// Whether `peer` may see the jobs and history entries of `uid`, or cancel
+/// its jobs: its own ones, or anyone's for admins.
+pub fn may_access(config: &ServerConfig, peer: &Peer, uid: u32) -> bool {
+    config.acl.is_empty()
+        || peer.uid == 0
+        || peer.uid == uid
+        || config
+            .acl
+            .iter()
+            .any(|rule| rule.admin && rule.applies_to(peer))
+}
+
+/// Whether `pattern` allows `command`. A bare `*` allows any command. Other
+/// patterns are matched word by word, and path component by path component
+/// within a word, each with `glob_match`, so that `*` and `?` stay within a
+/// word and never cross a `/`. They never allow a command containing shell
+/// metacharacters or a `..` path component.
+pub fn command_matches(pattern: &str, command: &str) -> bool {
+    if pattern.trim() == "*" {
+        return true;
+    }
+    if command.contains(SHELL_METACHARACTERS) {
+        return false;
+    }
+
+    let pattern_words: Vec<&str> = pattern.split_whitespace().collect();
+    let words: Vec<&str> = command.split_whitespace().collect();
+    pattern_words.len() == words.len()
+        && pattern_words
+            .iter()
+            .zip(&words)
+            .all(|(pattern_word, word)| word_matches(pattern_word, word))
+}
+
+fn word_matches(pattern: &str, word: &str) -> bool {
+    let pattern_components: Vec<&str> = pattern.split('/').collect();
+    let components: Vec<&str> = word.split('/').collect();
+    !components.contains(&"..")
+        && pattern_components.len() == components.len()
+        && pattern_components
+            .iter()
+            .zip(&components)
+            .all(|(pattern_component, component)| glob_match(pattern_component, component))
+}
+
+/// Matches `text` against `pattern`, where `*` matches any run of characters
+/// and `?` a single one.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where to resume from when what followed the last `*` didn't match.
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn user_name(uid: u32) -> Option<String> {
+    let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result = std::ptr::null_mut();
+    let ret =
+        unsafe { libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if ret != 0 || result.is_null() {
+        return None;
+    }
+
+    let name = unsafe { CStr::from_ptr(passwd.pw_name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+fn group_name(gid: u32) -> Option<String> {
+    let mut group: libc::group = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result = std::ptr::null_mut();
+    let ret =
+        unsafe { libc::getgrgid_r(gid, &mut group, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if ret != 0 || result.is_null() {
+        return None;
+    }
+
+    let name = unsafe { CStr::from_ptr(group.gr_name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+/// Group ids of `user`, `gid` included.
+fn group_list(user: &str, gid: u32) -> Vec<u32> {
+    let name = match CString::new(user) {
+        Ok(name) => name,
+        Err(_) => return vec![gid],
+    };
+    let mut count: libc::c_int = 32;
+    loop {
+        let mut gids = vec![0 as libc::gid_t; count as usize];
+        let ret = unsafe { libc::getgrouplist(name.as_ptr(), gid, gids.as_mut_ptr(), &mut count) };
+        if ret >= 0 {
+            gids.truncate(count as usize);
+            return gids;
+        }
+        // `count` now holds the number of groups, try again with enough room.
+        if count as usize <= gids.len() {
+            count = (gids.len() * 2) as libc::c_int;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{CmdRequest, StatusRequest, Timeouts};
+
+    fn config() -> ServerConfig {
+        let mut config = ServerConfig::default();
+        for name in ["web1", "db1"] {
+            config.nodes.insert(
+                name.to_string(),
+                toml::from_str("host = \"10.0.0.1\"").unwrap(),
+            );
+        }
+        config.acl.push(AclRule {
+            users: vec!["deploy".to_string()],
+            nodes: vec!["web1".to_string()],
+            commands: vec!["uptime".to_string()],
+            ..AclRule::default()
+        });
+
+        config
+    }
+
+    fn peer() -> Peer {
+        Peer {
+            uid: 1000,
+            gid: 1000,
+            pid: 1,
+            user: Some("deploy".to_string()),
+            groups: Vec::new(),
+        }
+    }
+
+    fn cmd(node: &str) -> Request {
+        Request::Cmd(CmdRequest {
+            nodes: vec![node.to_string()],
+            command: "uptime".to_string(),
+            stream: false,
+            timeouts: Timeouts::default(),
+            parallelism: None,
+            batch: None,
+        })
+    }
+
+    #[test]
+    fn unknown_nodes_are_denied_like_forbidden_ones() {
+        let config = config();
+        assert!(authorize(&config, &peer(), &cmd("web1")).is_ok());
+
+        let forbidden = authorize(&config, &peer(), &cmd("db1")).unwrap_err();
+        let unknown = authorize(&config, &peer(), &cmd("db2")).unwrap_err();
+        assert_eq!(forbidden.replace("db1", "db2"), unknown);
+    }
+
+    #[test]
+    fn status_is_for_admins() {
+        let mut config = config();
+        let status = Request::Status(StatusRequest {});
+        assert!(authorize(&config, &peer(), &status).is_err());
+
+        config.acl[0].admin = true;
+        assert!(authorize(&config, &peer(), &status).is_ok());
+    }
+
+    #[test]
+    fn command_pattern_matches_words() {
+        assert!(command_matches("uptime", "uptime"));
+        assert!(command_matches(
+            "systemctl restart *",
+            "systemctl restart nginx"
+        ));
+        assert!(command_matches(
+            "systemctl restart *",
+            "systemctl  restart\tnginx"
+        ));
+        assert!(command_matches(
+            "systemctl restart nginx?",
+            "systemctl restart nginx2"
+        ));
+        assert!(!command_matches("uptime", "uptime -p"));
+        assert!(!command_matches("systemctl restart *", "systemctl restart"));
+        assert!(!command_matches(
+            "systemctl restart *",
+            "systemctl stop nginx"
+        ));
+    }
+
+    #[test]
+    fn star_stays_within_a_word() {
+        assert!(!command_matches(
+            "systemctl restart *",
+            "systemctl restart nginx --now"
+        ));
+        assert!(!command_matches(
+            "cat /var/log/*",
+            "cat /var/log/syslog /etc/shadow"
+        ));
+    }
+
+    #[test]
+    fn shell_metacharacters_are_rejected() {
+        for command in [
+            "systemctl restart x; rm -rf /",
+            "systemctl restart x|sh",
+            "systemctl restart x&&reboot",
+            "systemctl restart x&",
+            "systemctl restart $(reboot)",
+            "systemctl restart `reboot`",
+            "systemctl restart (reboot)",
+            "systemctl restart x>/etc/passwd",
+            "systemctl restart x</etc/shadow",
+            "systemctl restart x\nreboot",
+        ] {
+            assert!(
+                !command_matches("systemctl restart *", command),
+                "'{}' was allowed",
+                command
+            );
+        }
+    }
+
+    #[test]
+    fn star_stays_within_a_path_component() {
+        assert!(command_matches("cat /var/log/*", "cat /var/log/syslog"));
+        assert!(command_matches(
+            "cat /var/log/*/*.log",
+            "cat /var/log/nginx/a.log"
+        ));
+        for command in [
+            "cat /var/log/../../etc/shadow",
+            "cat /var/log/nginx/access.log",
+            "cat /var/log/..",
+            "cat /var/log/x/../../../etc/shadow",
+        ] {
+            assert!(
+                !command_matches("cat /var/log/*", command),
+                "'{}' was allowed",
+                command
+            );
+        }
+        assert!(!command_matches("cat /var/*/*", "cat /var/../etc"));
+        assert!(!command_matches("ls *", "ls .."));
+    }
+
+    #[test]
+    fn shell_expansions_are_rejected() {
+        for command in [
+            "cat /var/log/x /etc/{shadow,}",
+            "cat /var/log/{..,x}",
+            "cat /var/log/*",
+            "cat /var/log/sys?og",
+            "cat /var/log/[s]yslog",
+            "cat ~root",
+            "cat '/etc/shadow'",
+            "cat \"/etc/shadow\"",
+            "cat /var/log/x\\ /etc/shadow",
+        ] {
+            assert!(
+                !command_matches("cat /var/log/*", command) && !command_matches("cat *", command),
+                "'{}' was allowed",
+                command
+            );
+        }
+    }
+
+    #[test]
+    fn bare_star_allows_anything() {
+        assert!(command_matches("*", "systemctl restart x; rm -rf /"));
+        assert!(command_matches(" * ", "echo $HOME | wc -c"));
+    }
+
+    #[test]
+    fn glob_matches_whole_text() {
+        assert!(glob_match("web-*", "web-1"));
+        assert!(glob_match("web-?", "web-1"));
+        assert!(glob_match("*-1", "web-1"));
+        assert!(glob_match("w*b*1", "web-1"));
+        assert!(!glob_match("web-?", "web-10"));
+        assert!(!glob_match("web", "web-1"));
+    }
+}
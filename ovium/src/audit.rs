@@ -0,0 +1,513 @@
+use crate::acl::Peer;
+use crate::server::ServerConfig;
+use crate::types::*;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditConfig {
+    /// JSON lines file every request is appended to, relative to the config
+    /// directory. No audit log is kept when unset.
+    pub path: Option<PathBuf>,
+    /// Size in bytes past which the log is rotated.
+    #[serde(default = "default_max_size")]
+    pub max_size: u64,
+    /// Number of rotated logs kept, as `path.1` (the newest) to `path.N`.
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            path: None,
+            max_size: default_max_size(),
+            keep: default_keep(),
+        }
+    }
+}
+
+fn default_max_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_keep() -> usize {
+    5
+}
+
+/// One line of the audit log. A request gets a `start` line once it's
+/// accepted, then an `end` line once it's over. One rejected before that,
+/// e.g. because of a failed handshake, only gets the `end` line.
+#[derive(Serialize, Debug)]
+pub struct AuditRecord {
+    /// Id of the request in the daemon's log lines.
+    pub request_id: u64,
+    pub phase: AuditPhase,
+    /// Seconds since the epoch.
+    pub started_at: f64,
+    pub ended_at: Option<f64>,
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: i32,
+    pub user: Option<String>,
+    pub request: &'static str,
+    /// Nodes the request was resolved to.
+    pub nodes: Vec<String>,
+    pub command: Option<String>,
+    /// Remote path of a file transfer.
+    pub path: Option<String>,
+    /// Why the request as a whole failed, e.g. it was forbidden.
+    pub error: Option<String>,
+    pub results: Vec<NodeOutcome>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditPhase {
+    Start,
+    End,
+}
+
+/// What happened on a single node.
+#[derive(Serialize, Debug)]
+pub struct NodeOutcome {
+    pub node: String,
+    /// One of `success`, `failure`, `timeout`, `skipped` or `aborted`.
+    pub status: &'static str,
+    pub exit_code: Option<i32>,
+    pub error: Option<String>,
+}
+
+impl From<&CmdReturn> for NodeOutcome {
+    fn from(cmd_return: &CmdReturn) -> Self {
+        let (status, exit_code, error) = match &cmd_return.data {
+            SshReturn::SshSuccess(success) => ("success", Some(success.exit_status), None),
+            SshReturn::SshFailure(failure) => ("failure", None, Some(failure.clone())),
+            SshReturn::SshTimeout(stage, seconds) => (
+                "timeout",
+                None,
+                Some(format!("{} timed out after {}s", stage, seconds)),
+            ),
+            SshReturn::SshSkipped(_) => ("skipped", None, None),
+            SshReturn::SshAborted => ("aborted", None, None),
+        };
+
+        NodeOutcome {
+            node: cmd_return.node_name.clone(),
+            status,
+            exit_code,
+            error,
+        }
+    }
+}
+
+impl From<&TransferReturn> for NodeOutcome {
+    fn from(transfer_return: &TransferReturn) -> Self {
+        let (status, error) = match &transfer_return.data {
+            TransferResult::Uploaded(_) | TransferResult::Downloaded { .. } => ("success", None),
+            TransferResult::SshFailure(failure) => ("failure", Some(failure.clone())),
+            TransferResult::SshTimeout(stage, seconds) => (
+                "timeout",
+                Some(format!("{} timed out after {}s", stage, seconds)),
+            ),
+        };
+
+        NodeOutcome {
+            node: transfer_return.node_name.clone(),
+            status,
+            exit_code: None,
+            error,
+        }
+    }
+}
+
+/// Collects the per-node outcomes of a request while its handler runs.
+#[derive(Clone, Default, Debug)]
+pub struct AuditTrail {
+    outcomes: Arc<Mutex<Vec<NodeOutcome>>>,
+}
+
+impl AuditTrail {
+    pub fn record(&self, outcome: NodeOutcome) {
+        self.outcomes.lock().unwrap().push(outcome);
+    }
+
+    fn take(&self) -> Vec<NodeOutcome> {
+        std::mem::take(&mut self.outcomes.lock().unwrap())
+    }
+}
+
+impl AuditRecord {
+    /// Starts the record of `request`, made by `peer`.
+    pub fn start(
+        request_id: u64,
+        peer: &Peer,
+        request: &Request,
+        config: &ServerConfig,
+    ) -> AuditRecord {
+        let (nodes, command, path) = match request {
+            Request::Cmd(req) | Request::Submit(SubmitRequest { cmd: req }) => {
+                (Some(&req.nodes), Some(req.command.clone()), None)
+            }
+            Request::Upload(req) => (Some(&req.nodes), None, Some(req.destination.clone())),
+            Request::Download(req) => (Some(&req.nodes), None, Some(req.source.clone())),
+            Request::Hello(_)
+            | Request::Status(_)
+            | Request::Reload(_)
+            | Request::Job(_)
+            | Request::History(_)
+            | Request::Rerun(_) => (None, None, None),
+        };
+
+        AuditRecord {
+            request: request.kind(),
+            nodes: nodes
+                .map(|names| config.resolve_nodes(names).into_iter().cloned().collect())
+                .unwrap_or_default(),
+            command,
+            path,
+            ..AuditRecord::handshake(request_id, peer)
+        }
+    }
+
+    /// Starts the record of a connection from `peer` that didn't get past
+    /// the handshake.
+    pub fn handshake(request_id: u64, peer: &Peer) -> AuditRecord {
+        AuditRecord {
+            request_id,
+            phase: AuditPhase::Start,
+            started_at: now(),
+            ended_at: None,
+            uid: peer.uid,
+            gid: peer.gid,
+            pid: peer.pid,
+            user: peer.user.clone(),
+            request: "hello",
+            nodes: Vec::new(),
+            command: None,
+            path: None,
+            error: None,
+            results: Vec::new(),
+        }
+    }
+
+    /// Completes the record with what `trail` collected, making it the
+    /// `end` line.
+    pub fn finish(&mut self, trail: &AuditTrail, error: Option<String>) {
+        self.phase = AuditPhase::End;
+        self.ended_at = Some(now());
+        self.error = error;
+        self.results = trail.take();
+    }
+}
+
+/// Seconds since the epoch.
+pub fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs_f64())
+        .unwrap_or_default()
+}
+
+/// Append-only JSON lines log of the requests, rotated by size.
+pub struct AuditLog {
+    inner: Mutex<AuditFile>,
+}
+
+struct AuditFile {
+    config: AuditConfig,
+    file: Option<File>,
+}
+
+/// Settings of a reloaded configuration, ready to be applied.
+pub struct PreparedAudit {
+    config: AuditConfig,
+    /// The reopened log, when it moved.
+    file: Option<AuditFile>,
+}
+
+impl AuditLog {
+    pub fn new(config: &AuditConfig) -> io::Result<AuditLog> {
+        Ok(AuditLog {
+            inner: Mutex::new(AuditFile::open(config)?),
+        })
+    }
+
+    /// Gets the settings of a reloaded configuration ready, opening the log
+    /// if it moved. Nothing changes until they're applied.
+    pub fn prepare(&self, config: &AuditConfig) -> io::Result<PreparedAudit> {
+        let inner = self.inner.lock().unwrap();
+        let file = if inner.config.path != config.path {
+            Some(AuditFile::open(config)?)
+        } else {
+            None
+        };
+
+        Ok(PreparedAudit {
+            config: config.clone(),
+            file,
+        })
+    }
+
+    /// Swaps in settings from `prepare`.
+    pub fn apply(&self, prepared: PreparedAudit) {
+        let mut inner = self.inner.lock().unwrap();
+        match prepared.file {
+            Some(file) => *inner = file,
+            None => inner.config = prepared.config,
+        }
+    }
+
+    pub fn write(&self, record: &AuditRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let mut inner = self.inner.lock().unwrap();
+        let size = match &inner.file {
+            Some(file) => file.metadata()?.len(),
+            None => return Ok(()),
+        };
+        if size > 0 && size + line.len() as u64 > inner.config.max_size {
+            inner.rotate()?;
+        }
+        if let Some(file) = inner.file.as_mut() {
+            file.write_all(&line)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl AuditFile {
+    fn open(config: &AuditConfig) -> io::Result<AuditFile> {
+        let file = match &config.path {
+            Some(path) => Some(open_append(path)?),
+            None => None,
+        };
+
+        Ok(AuditFile {
+            config: config.clone(),
+            file,
+        })
+    }
+
+    /// Shifts `path.N` to `path.N+1`, dropping the oldest, then starts over
+    /// with an empty `path`.
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = match &self.config.path {
+            Some(path) => path.clone(),
+            None => return Ok(()),
+        };
+        let rotated = |n: usize| {
+            let mut name = path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+
+        if self.config.keep == 0 {
+            fs::remove_file(&path)?;
+        } else {
+            for n in (1..self.config.keep).rev() {
+                if rotated(n).exists() {
+                    fs::rename(rotated(n), rotated(n + 1))?;
+                }
+            }
+            fs::rename(&path, rotated(1))?;
+        }
+        self.file = Some(open_append(&path)?);
+
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> Peer {
+        Peer {
+            uid: 1000,
+            gid: 1000,
+            pid: 1,
+            user: Some("deploy".to_string()),
+            groups: Vec::new(),
+        }
+    }
+
+    /// A fresh directory for the logs of test `name`.
+    fn log_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ovium-audit-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    fn config(path: &Path, max_size: u64, keep: usize) -> AuditConfig {
+        AuditConfig {
+            path: Some(path.to_path_buf()),
+            max_size,
+            keep,
+        }
+    }
+
+    /// Request ids of the records in `path`, oldest first.
+    fn ids(path: &Path) -> Vec<u64> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<serde_json::Value>(line).unwrap()["request_id"]
+                    .as_u64()
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    fn write(log: &AuditLog, request_id: u64) {
+        let mut record = AuditRecord::handshake(request_id, &peer());
+        record.finish(&AuditTrail::default(), None);
+        log.write(&record).unwrap();
+    }
+
+    #[test]
+    fn rotation_shifts_the_numbered_logs() {
+        let dir = log_dir("rotate");
+        let path = dir.join("audit.log");
+        // Every record is past the size, each one rotates the log.
+        let log = AuditLog::new(&config(&path, 1, 2)).unwrap();
+        for request_id in 1..=4 {
+            write(&log, request_id);
+        }
+
+        assert_eq!(ids(&path), [4]);
+        assert_eq!(ids(&dir.join("audit.log.1")), [3]);
+        assert_eq!(ids(&dir.join("audit.log.2")), [2]);
+        assert!(!dir.join("audit.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotation_without_keeping_starts_over() {
+        let dir = log_dir("keep-0");
+        let path = dir.join("audit.log");
+        let log = AuditLog::new(&config(&path, 1, 0)).unwrap();
+        for request_id in 1..=3 {
+            write(&log, request_id);
+        }
+
+        assert_eq!(ids(&path), [3]);
+        assert!(!dir.join("audit.log.1").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn log_rotates_once_the_next_record_would_exceed_its_size() {
+        let dir = log_dir("size");
+        let path = dir.join("audit.log");
+        let log = AuditLog::new(&config(&path, u64::MAX, 5)).unwrap();
+        write(&log, 1);
+        let line_size = fs::metadata(&path).unwrap().len();
+
+        // Room for two records but not three, their sizes vary a bit.
+        let log = AuditLog::new(&config(&path, line_size * 5 / 2, 5)).unwrap();
+        write(&log, 2);
+        assert_eq!(ids(&path), [1, 2]);
+        assert!(!dir.join("audit.log.1").exists());
+
+        write(&log, 3);
+        assert_eq!(ids(&path), [3]);
+        assert_eq!(ids(&dir.join("audit.log.1")), [1, 2]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn moved_log_is_used_once_applied() {
+        let dir = log_dir("move");
+        let (old, new) = (dir.join("old.log"), dir.join("new.log"));
+        let log = AuditLog::new(&config(&old, u64::MAX, 5)).unwrap();
+
+        let prepared = log.prepare(&config(&new, u64::MAX, 5)).unwrap();
+        write(&log, 1);
+        log.apply(prepared);
+        write(&log, 2);
+        assert_eq!(ids(&old), [1]);
+        assert_eq!(ids(&new), [2]);
+
+        // A log that can't be opened leaves the current one in place.
+        let unreachable = dir.join("missing").join("audit.log");
+        assert!(log.prepare(&config(&unreachable, u64::MAX, 5)).is_err());
+        write(&log, 3);
+        assert_eq!(ids(&new), [2, 3]);
+
+        // Other settings for the same log apply without reopening it.
+        log.apply(log.prepare(&config(&new, 1, 0)).unwrap());
+        write(&log, 4);
+        assert_eq!(ids(&new), [4]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn request_gets_a_start_and_an_end_line() {
+        let dir = log_dir("phases");
+        let path = dir.join("audit.log");
+        let log = AuditLog::new(&config(&path, u64::MAX, 5)).unwrap();
+        let mut server_config = ServerConfig::default();
+        server_config.nodes.insert(
+            "web1".to_string(),
+            toml::from_str("host = \"10.0.0.1\"").unwrap(),
+        );
+        let request = Request::Cmd(CmdRequest {
+            nodes: vec!["web*".to_string()],
+            command: "uptime".to_string(),
+            stream: false,
+            timeouts: Timeouts::default(),
+            parallelism: None,
+            batch: None,
+        });
+
+        let mut record = AuditRecord::start(7, &peer(), &request, &server_config);
+        log.write(&record).unwrap();
+        let trail = AuditTrail::default();
+        trail.record(NodeOutcome {
+            node: "web1".to_string(),
+            status: "success",
+            exit_code: Some(0),
+            error: None,
+        });
+        record.finish(&trail, None);
+        log.write(&record).unwrap();
+
+        let lines: Vec<serde_json::Value> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        for line in &lines {
+            assert_eq!(line["request_id"], 7);
+            assert_eq!(line["command"], "uptime");
+            assert_eq!(line["nodes"], serde_json::json!(["web1"]));
+        }
+        assert_eq!(lines[0]["phase"], "start");
+        assert!(lines[0]["ended_at"].is_null());
+        assert!(lines[0]["results"].as_array().unwrap().is_empty());
+        assert_eq!(lines[1]["phase"], "end");
+        assert!(lines[1]["ended_at"].is_f64());
+        assert_eq!(lines[1]["results"][0]["status"], "success");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,23 +1,49 @@
-use ovium::client::{Cli, Client};
-use ovium::error::{ErrorKind, OviumError};
-use ovium::types::*;
-use simplelog::{Config, LevelFilter, TermLogger, TerminalMode};
+use ovium::client::{Cli, Client, ExitStatus, Verdict};
+use ovium::error::{Error, ErrorKind, OviumError};
+use ovium::handlers::save_download;
+use ovium::logging::{self, LogConfig};
+use ovium::output::Printer;
+use ovium::types::{set_colors, Request, Response};
 use std::env;
+use std::io::{self, IsTerminal};
+use std::process;
 
-fn main() -> Result<(), OviumError> {
-    match TermLogger::init(LevelFilter::Info, Config::default(), TerminalMode::Mixed) {
-        Ok(_) => (),
-        Err(err) => eprintln!("Failed while setting up logger: {}", err),
-    }
-
+fn main() {
     let args: Vec<String> = env::args().collect();
     let cli = Cli::new(args);
-    let (socket_path, request) = cli.parse();
-    let response = Client::new(&socket_path)
-        .run(request)
-        .map_err(|err| (ErrorKind::ClientRun, err))?;
-    let handler = ClientHandler::new(response);
-    handler.handle().unwrap();
+    let cli_args = cli.parse();
+
+    if let Err(err) = logging::init(&LogConfig::default(), cli_args.verbosity, "oviumctl") {
+        eprintln!("Failed while setting up logger: {}", err);
+    }
+    set_colors(io::stdout().is_terminal());
+    let mut printer = Printer::new(cli_args.output);
+    let mut verdict = Verdict::new(cli_args.fail_policy);
+    let download_destination = match &cli_args.request {
+        Request::Download(download_request) => Some(download_request.destination.clone()),
+        _ => None,
+    };
+    let local = |err| Error::Local(Box::new(err));
+    let run = Client::new(&cli_args.socket_path)
+        .run(cli_args.request, |mut response| {
+            if let (Some(destination), Response::Transfer(transfer_return)) =
+                (&download_destination, &mut response)
+            {
+                save_download(destination, transfer_return).map_err(local)?;
+            }
+            let flow = verdict.record(&response);
+            printer.handle(response).map_err(local)?;
+            Ok(flow)
+        })
+        .and_then(|_| printer.finish().map_err(local));
 
-    Ok(())
+    let exit_status = match run {
+        Ok(()) => verdict.exit_status(),
+        Err(err) => {
+            let exit_status = ExitStatus::from(&err);
+            eprintln!("{}", OviumError::from((ErrorKind::ClientRun, err)));
+            exit_status
+        }
+    };
+    process::exit(exit_status as i32);
 }
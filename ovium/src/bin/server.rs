@@ -1,19 +1,17 @@
 use getopts::Options;
+use ovium::logging::{self, LogConfig};
 use ovium::server::Server;
-use simplelog::{Config, LevelFilter, TermLogger, TerminalMode};
+use std::path::Path;
 use std::{env, process};
 
 fn main() {
-    match TermLogger::init(LevelFilter::Info, Config::default(), TerminalMode::Mixed) {
-        Ok(_) => (),
-        Err(err) => eprintln!("Failed while setting up logger: {}", err),
-    }
-
     let args: Vec<_> = env::args().collect();
     let program_name = &args[0];
     let mut opts = Options::new();
     opts.optopt("s", "", "socket path to listen on", "SOCK");
     opts.optopt("c", "", "config files directory", "CONFIG-DIR");
+    opts.optflagmulti("v", "verbose", "log more, repeat for even more");
+    opts.optflagmulti("q", "quiet", "log less, repeat for even less");
     opts.optflag("h", "help", "print this help menu");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -33,6 +31,12 @@ fn main() {
         }
     };
 
+    let verbosity = matches.opt_count("v") as i32 - matches.opt_count("q") as i32;
+    let log_config = LogConfig::load(Path::new(&config_path));
+    if let Err(err) = logging::init(&log_config, verbosity, "oviumd") {
+        eprintln!("Failed while setting up logger: {}", err);
+    }
+
     if let Some(s) = matches.opt_str("s") {
         let server = match Server::new(&s, &config_path) {
             Ok(server) => server,
@@ -1,35 +1,209 @@
-use crate::error::Error;
+use crate::error::{Error, RequestError};
+use crate::output::OutputFormat;
 use crate::types::*;
+use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone};
 use getopts::Options;
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::ops::ControlFlow;
 use std::os::unix::net::UnixStream;
 use std::process;
+use std::str::FromStr;
 
 pub struct Client<'a> {
     pub socket_path: &'a str,
 }
 
 impl Client<'_> {
-    pub fn new(socket_path: &str) -> Client {
+    pub fn new(socket_path: &str) -> Client<'_> {
         Client { socket_path }
     }
 
-    pub fn run(self, request: Request) -> Result<Response, Error> {
+    /// Sends `request` and passes every response to `on_response` as it
+    /// arrives, until the server closes the connection or `on_response`
+    /// breaks.
+    pub fn run<F>(self, request: Request, mut on_response: F) -> Result<(), Error>
+    where
+        F: FnMut(Response) -> Result<ControlFlow<()>, Error>,
+    {
         let stream = UnixStream::connect(self.socket_path)?;
         let mut reader = BufReader::new(&stream);
         let mut writer = BufWriter::new(&stream);
 
-        let mut resp = Vec::new();
+        writer.write_all(&Request::Hello(Hello::default()).encode()?)?;
+        writer.flush()?;
+        let server_hello = match Response::read_from(&mut reader)? {
+            Some(Response::Hello(hello)) => hello,
+            // The server explains why it refused the handshake.
+            Some(response) => return on_response(response).map(|_| ()),
+            None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        };
+
+        for capability in request.required_capabilities() {
+            if !server_hello.supports(capability) {
+                return Err(RequestError::UnsupportedCapability(capability.to_string()).into());
+            }
+        }
+
         writer.write_all(&request.encode()?)?;
         writer.flush()?;
-        reader.read_until(b'\n', &mut resp)?;
 
-        let response = Response::decode(&resp)?;
+        while let Some(response) = Response::read_from(&mut reader)? {
+            if on_response(response)?.is_break() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Exit codes of `oviumctl`, from the best to the worst outcome.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ExitStatus {
+    /// Every node ran the request and commands exited with 0.
+    Success = 0,
+    /// Some commands exited with a non-zero status.
+    NonZero = 1,
+    /// Some nodes couldn't be reached, timed out, were skipped or aborted.
+    Transport = 2,
+    /// The server rejected the request, e.g. because of unknown nodes.
+    Rejected = 3,
+    /// The server couldn't be reached, or the exchange with it failed.
+    Unreachable = 4,
+    /// The command line is invalid.
+    Usage = 5,
+    /// Handling the results failed on this host, e.g. saving a download.
+    Local = 6,
+}
+
+impl From<&Error> for ExitStatus {
+    fn from(err: &Error) -> Self {
+        match err {
+            Error::RequestError(_) => ExitStatus::Rejected,
+            Error::Local(_) => ExitStatus::Local,
+            _ => ExitStatus::Unreachable,
+        }
+    }
+}
+
+/// How the status of each node makes the overall `ExitStatus`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailPolicy {
+    /// The worst status of all nodes.
+    Any,
+    /// The best status of all nodes, failing only when all nodes failed.
+    All,
+    /// Like `Any`, but stops waiting for results at the first node that
+    /// doesn't succeed. The server still runs the command on the others.
+    Fast,
+}
+
+impl FromStr for FailPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "any" => Ok(FailPolicy::Any),
+            "all" => Ok(FailPolicy::All),
+            "fast" => Ok(FailPolicy::Fast),
+            _ => Err(format!("'{}' is not one of any, all or fast", policy)),
+        }
+    }
+}
+
+/// Works out the `ExitStatus` of a request from its responses.
+pub struct Verdict {
+    policy: FailPolicy,
+    worst: ExitStatus,
+    best: Option<ExitStatus>,
+    rejected: bool,
+}
+
+impl Verdict {
+    pub fn new(policy: FailPolicy) -> Verdict {
+        Verdict {
+            policy,
+            worst: ExitStatus::Success,
+            best: None,
+            rejected: false,
+        }
+    }
+
+    /// Records `response`, breaking when the fail-fast policy says the
+    /// rest of the responses don't matter anymore. Only the client stops
+    /// reading, the request goes on on the server.
+    pub fn record(&mut self, response: &Response) -> ControlFlow<()> {
+        let statuses: Vec<ExitStatus> = match response {
+            Response::Cmd(cmd_returns) => cmd_returns.iter().map(cmd_status).collect(),
+            Response::CmdStatus(cmd_return) => vec![cmd_status(cmd_return)],
+            Response::Transfer(transfer_return) => vec![transfer_status(transfer_return)],
+            Response::Error(_) => {
+                self.rejected = true;
+                return ControlFlow::Break(());
+            }
+            Response::CmdOutput(_)
+            | Response::Hello(_)
+            | Response::Status(_)
+            | Response::Reload(_)
+            | Response::Job(_)
+            | Response::Jobs(_)
+            | Response::History(_)
+            | Response::HistoryEntry(_) => vec![],
+        };
+
+        for status in statuses {
+            self.worst = self.worst.max(status);
+            self.best = Some(self.best.map_or(status, |best| best.min(status)));
+        }
+
+        if self.policy == FailPolicy::Fast && self.worst != ExitStatus::Success {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        }
+    }
 
-        Ok(response)
+    pub fn exit_status(&self) -> ExitStatus {
+        if self.rejected {
+            return ExitStatus::Rejected;
+        }
+
+        match self.policy {
+            FailPolicy::Any | FailPolicy::Fast => self.worst,
+            FailPolicy::All => self.best.unwrap_or(ExitStatus::Success),
+        }
     }
 }
 
+fn cmd_status(cmd_return: &CmdReturn) -> ExitStatus {
+    match &cmd_return.data {
+        SshReturn::SshSuccess(success) if success.exit_status == 0 => ExitStatus::Success,
+        SshReturn::SshSuccess(_) => ExitStatus::NonZero,
+        SshReturn::SshFailure(_)
+        | SshReturn::SshTimeout(..)
+        | SshReturn::SshSkipped(_)
+        | SshReturn::SshAborted => ExitStatus::Transport,
+    }
+}
+
+fn transfer_status(transfer_return: &TransferReturn) -> ExitStatus {
+    match &transfer_return.data {
+        TransferResult::Uploaded(_) | TransferResult::Downloaded { .. } => ExitStatus::Success,
+        TransferResult::SshFailure(_) | TransferResult::SshTimeout(..) => ExitStatus::Transport,
+    }
+}
+
+/// What `Cli::parse` got out of the command line.
+pub struct CliArgs {
+    pub socket_path: String,
+    pub request: Request,
+    pub output: OutputFormat,
+    pub fail_policy: FailPolicy,
+    /// Number of `-v` minus number of `-q`.
+    pub verbosity: i32,
+}
+
 pub struct Cli {
     opts: getopts::Options,
     args: Vec<String>,
@@ -40,17 +214,98 @@ impl Cli {
         let mut opts = Options::new();
         opts.optopt("s", "", "server socket path", "sock");
         opts.optopt("c", "", "remote command to launch", "command");
-        opts.optopt("n", "", "nodes to manage", "nodes");
+        opts.optopt("n", "", "nodes to manage, see SELECTOR", "SELECTOR");
+        opts.optflag("f", "follow", "print command output as it is produced");
+        opts.optopt("t", "timeout", "command timeout on each node", "SECONDS");
+        opts.optopt("", "connect-timeout", "ssh connection timeout", "SECONDS");
+        opts.optopt("", "handshake-timeout", "ssh handshake timeout", "SECONDS");
+        opts.optopt(
+            "p",
+            "parallel",
+            "maximum number (or percentage) of nodes to run on at once",
+            "N|N%",
+        );
+        opts.optflag(
+            "b",
+            "batch",
+            "run nodes in serial batches of --parallel nodes, which is required",
+        );
+        opts.optopt(
+            "",
+            "max-failure",
+            "failure ratio of a batch that stops the rollout (default: 0)",
+            "RATIO",
+        );
+        opts.optopt(
+            "",
+            "mode",
+            "mode of uploaded files (default: 0644)",
+            "OCTAL",
+        );
+        opts.optopt("", "owner", "owner of uploaded files", "USER[:GROUP]");
+        opts.optflag(
+            "",
+            "submit",
+            "run the command in the background and print its job id",
+        );
+        opts.optflag(
+            "",
+            "failed",
+            "history: only requests where some node failed",
+        );
+        opts.optopt(
+            "",
+            "exit-code",
+            "history: only requests where some node exited with CODE",
+            "CODE",
+        );
+        opts.optopt(
+            "",
+            "since",
+            "history: only requests started from DATE on",
+            "YYYY-MM-DD[ HH:MM[:SS]]",
+        );
+        opts.optopt(
+            "",
+            "until",
+            "history: only requests started up to DATE",
+            "YYYY-MM-DD[ HH:MM[:SS]]",
+        );
+        opts.optopt(
+            "",
+            "limit",
+            "history: number of requests listed (default: 20)",
+            "N",
+        );
+        opts.optflag("", "status", "print the server status");
+        opts.optflag("", "reload", "make the server reload its configuration");
+        opts.optopt(
+            "o",
+            "output",
+            "output format: text, json, jsonl or yaml (default: text)",
+            "FORMAT",
+        );
+        opts.optopt(
+            "",
+            "fail-policy",
+            "how node results make the exit code: any, all or fast (default: any)",
+            "POLICY",
+        );
+        opts.optflagmulti("v", "verbose", "log more, repeat for even more");
+        opts.optflagmulti("q", "quiet", "log less, repeat for even less");
         opts.optflag("h", "help", "print this help menu");
 
         Cli { opts, args }
     }
 
-    pub fn parse(&self) -> (String, Request) {
+    pub fn parse(&self) -> CliArgs {
         let program_name = &self.args[0];
         let matches = match self.opts.parse(&self.args[1..]) {
             Ok(m) => m,
-            Err(f) => panic!("{}", f.to_string()),
+            Err(f) => {
+                eprintln!("{}!", f);
+                process::exit(ExitStatus::Usage as i32);
+            }
         };
 
         if matches.opt_present("h") || self.args.len() < 2 {
@@ -62,26 +317,489 @@ impl Cli {
             Some(s) => s,
             None => {
                 eprintln!("socket path is required!");
-                process::exit(1);
+                process::exit(ExitStatus::Usage as i32);
             }
         };
 
-        if let Some(c) = matches.opt_str("c") {
-            if let Some(n) = matches.opt_str("n") {
-                let nodes: Vec<String> = n.split(',').map(String::from).collect();
-                let request = Request::Cmd(CmdRequest { nodes, command: c });
-                (socket_path, request)
-            } else {
+        let output = match matches.opt_str("o") {
+            None => OutputFormat::Text,
+            Some(format) => match format.parse() {
+                Ok(format) => format,
+                Err(err) => {
+                    eprintln!("{}!", err);
+                    process::exit(ExitStatus::Usage as i32);
+                }
+            },
+        };
+
+        let verbosity = matches.opt_count("v") as i32 - matches.opt_count("q") as i32;
+
+        let fail_policy = match matches.opt_str("fail-policy") {
+            None => FailPolicy::Any,
+            Some(policy) => match policy.parse() {
+                Ok(policy) => policy,
+                Err(err) => {
+                    eprintln!("{}!", err);
+                    process::exit(ExitStatus::Usage as i32);
+                }
+            },
+        };
+
+        if matches.opt_present("status") {
+            return CliArgs {
+                socket_path,
+                request: Request::Status(StatusRequest {}),
+                output,
+                fail_policy,
+                verbosity,
+            };
+        }
+        if matches.opt_present("reload") {
+            return CliArgs {
+                socket_path,
+                request: Request::Reload(ReloadRequest {}),
+                output,
+                fail_policy,
+                verbosity,
+            };
+        }
+
+        if matches.free.first().map(String::as_str) == Some("job") {
+            return CliArgs {
+                socket_path,
+                request: Request::Job(job_request(&matches.free)),
+                output,
+                fail_policy,
+                verbosity,
+            };
+        }
+
+        if matches.free.first().map(String::as_str) == Some("history") {
+            return CliArgs {
+                socket_path,
+                request: history_request(&matches),
+                output,
+                fail_policy,
+                verbosity,
+            };
+        }
+
+        let nodes: Vec<String> = match matches.opt_str("n") {
+            Some(n) => vec![n],
+            None => {
                 eprintln!("nodes list is required!");
-                process::exit(1);
+                process::exit(ExitStatus::Usage as i32);
             }
-        } else {
-            process::exit(1);
+        };
+        let timeouts = Timeouts {
+            connect: parse_seconds(&matches, "connect-timeout"),
+            handshake: parse_seconds(&matches, "handshake-timeout"),
+            exec: parse_seconds(&matches, "t"),
+        };
+
+        let request = match matches.free.first().map(String::as_str) {
+            Some("upload") => {
+                let (source, destination) = transfer_paths(&matches.free);
+                // Sent as a single frame, checked before reading it all.
+                match fs::metadata(&source) {
+                    Ok(metadata) if metadata.len() > MAX_TRANSFER_SIZE as u64 => {
+                        eprintln!(
+                            "'{}' is {} bytes, more than the {} bytes an upload can carry!",
+                            source,
+                            metadata.len(),
+                            MAX_TRANSFER_SIZE
+                        );
+                        process::exit(ExitStatus::Usage as i32);
+                    }
+                    _ => (),
+                }
+                let contents = match fs::read(&source) {
+                    Ok(contents) => contents,
+                    Err(err) => {
+                        eprintln!("unable to read '{}': {}", source, err);
+                        process::exit(ExitStatus::Usage as i32);
+                    }
+                };
+                Request::Upload(UploadRequest {
+                    nodes,
+                    destination,
+                    contents,
+                    mode: parse_mode(&matches),
+                    owner: matches.opt_str("owner"),
+                    timeouts,
+                })
+            }
+            Some("download") => {
+                let (source, destination) = transfer_paths(&matches.free);
+                Request::Download(DownloadRequest {
+                    nodes,
+                    source,
+                    destination,
+                    timeouts,
+                })
+            }
+            Some(subcommand) => {
+                eprintln!("unknown subcommand '{}'!", subcommand);
+                process::exit(ExitStatus::Usage as i32);
+            }
+            None => match matches.opt_str("c") {
+                Some(c) => {
+                    let parallelism = matches.opt_str("p").map(|p| parse_parallelism(&p));
+                    if matches.opt_present("b") && parallelism.is_none() {
+                        eprintln!("batch mode needs a batch size, set it with -p!");
+                        process::exit(ExitStatus::Usage as i32);
+                    }
+                    let batch = if matches.opt_present("b") {
+                        Some(BatchPolicy {
+                            max_failure_ratio: parse_ratio(&matches, "max-failure"),
+                        })
+                    } else {
+                        None
+                    };
+                    let cmd = CmdRequest {
+                        nodes,
+                        command: c,
+                        stream: matches.opt_present("f"),
+                        timeouts,
+                        parallelism,
+                        batch,
+                    };
+                    if matches.opt_present("submit") {
+                        Request::Submit(SubmitRequest { cmd })
+                    } else {
+                        Request::Cmd(cmd)
+                    }
+                }
+                None => process::exit(ExitStatus::Usage as i32),
+            },
+        };
+
+        CliArgs {
+            socket_path,
+            request,
+            output,
+            fail_policy,
+            verbosity,
+        }
+    }
+}
+
+/// Source and destination of an `upload` or `download` subcommand.
+fn transfer_paths(free: &[String]) -> (String, String) {
+    match free {
+        [_, source, destination] => (source.clone(), destination.clone()),
+        _ => {
+            eprintln!("{} takes a source and a destination!", free[0]);
+            process::exit(ExitStatus::Usage as i32);
+        }
+    }
+}
+
+/// Action and job id of a `job` subcommand.
+fn job_request(free: &[String]) -> JobRequest {
+    let free: Vec<&str> = free.iter().map(String::as_str).collect();
+    let id = |id: &str| match id.parse() {
+        Ok(id) => id,
+        Err(_) => {
+            eprintln!("'{}' is not a valid job id!", id);
+            process::exit(ExitStatus::Usage as i32);
+        }
+    };
+
+    match free[..] {
+        [_, "list"] => JobRequest::List,
+        [_, "status", job_id] => JobRequest::Status(id(job_id)),
+        [_, "wait", job_id] => JobRequest::Wait(id(job_id)),
+        [_, "output", job_id] => JobRequest::Output(id(job_id)),
+        [_, "cancel", job_id] => JobRequest::Cancel(id(job_id)),
+        _ => {
+            eprintln!("job takes list, or one of status, wait, output or cancel and a job id!");
+            process::exit(ExitStatus::Usage as i32);
+        }
+    }
+}
+
+/// Request of a `history` subcommand: a listing filtered by the options,
+/// or the `show` or `rerun` of an entry.
+fn history_request(matches: &getopts::Matches) -> Request {
+    let free: Vec<&str> = matches.free.iter().map(String::as_str).collect();
+    let id = |id: &str| match id.parse() {
+        Ok(id) => id,
+        Err(_) => {
+            eprintln!("'{}' is not a valid request id!", id);
+            process::exit(ExitStatus::Usage as i32);
+        }
+    };
+
+    match free[..] {
+        [_] | [_, "list"] => Request::History(HistoryRequest::List(HistoryFilter {
+            nodes: matches.opt_str("n").into_iter().collect(),
+            failed: matches.opt_present("failed"),
+            exit_code: matches.opt_str("exit-code").map(|code| match code.parse() {
+                Ok(code) => code,
+                Err(_) => {
+                    eprintln!("'{}' is not a valid exit code!", code);
+                    process::exit(ExitStatus::Usage as i32);
+                }
+            }),
+            since: matches.opt_str("since").map(|date| parse_date(&date)),
+            until: matches.opt_str("until").map(|date| parse_date(&date)),
+            limit: Some(match matches.opt_str("limit") {
+                None => 20,
+                Some(limit) => match limit.parse() {
+                    Ok(limit) => limit,
+                    Err(_) => {
+                        eprintln!("'{}' is not a valid limit!", limit);
+                        process::exit(ExitStatus::Usage as i32);
+                    }
+                },
+            }),
+        })),
+        [_, "show", request_id] => Request::History(HistoryRequest::Show(id(request_id))),
+        [_, "rerun", request_id] => Request::Rerun(RerunRequest {
+            id: id(request_id),
+            stream: matches.opt_present("f"),
+            timeouts: Timeouts {
+                connect: parse_seconds(matches, "connect-timeout"),
+                handshake: parse_seconds(matches, "handshake-timeout"),
+                exec: parse_seconds(matches, "t"),
+            },
+            parallelism: matches.opt_str("p").map(|p| parse_parallelism(&p)),
+        }),
+        _ => {
+            eprintln!("history takes list, or one of show or rerun and a request id!");
+            process::exit(ExitStatus::Usage as i32);
+        }
+    }
+}
+
+/// Seconds since the epoch of a local date, with an optional time.
+fn parse_date(date: &str) -> f64 {
+    let parsed = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(date, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .ok()
+                .and_then(|day| day.and_hms_opt(0, 0, 0))
+        })
+        .and_then(|time| Local.from_local_datetime(&time).earliest());
+
+    match parsed {
+        Some(time) => time.timestamp() as f64,
+        None => {
+            eprintln!("'{}' is not a valid date!", date);
+            process::exit(ExitStatus::Usage as i32);
+        }
+    }
+}
+
+fn parse_mode(matches: &getopts::Matches) -> i32 {
+    match matches.opt_str("mode") {
+        None => 0o644,
+        Some(mode) => match i32::from_str_radix(&mode, 8) {
+            Ok(mode) if (0..=0o7777).contains(&mode) => mode,
+            _ => {
+                eprintln!("'{}' is not a valid octal mode!", mode);
+                process::exit(ExitStatus::Usage as i32);
+            }
+        },
+    }
+}
+
+fn parse_seconds(matches: &getopts::Matches, name: &str) -> Option<u64> {
+    matches.opt_str(name).map(|seconds| match seconds.parse() {
+        Ok(seconds) => seconds,
+        Err(_) => {
+            eprintln!("'{}' is not a valid number of seconds!", seconds);
+            process::exit(ExitStatus::Usage as i32);
+        }
+    })
+}
+
+fn parse_parallelism(parallelism: &str) -> Parallelism {
+    let parsed = match parallelism.strip_suffix('%') {
+        Some(percent) => percent
+            .parse()
+            .ok()
+            .filter(|p| (1..=100).contains(p))
+            .map(Parallelism::Percent),
+        None => parallelism
+            .parse()
+            .ok()
+            .filter(|c| *c > 0)
+            .map(Parallelism::Count),
+    };
+
+    match parsed {
+        Some(parallelism) => parallelism,
+        None => {
+            eprintln!("'{}' is not a valid parallelism!", parallelism);
+            process::exit(ExitStatus::Usage as i32);
         }
     }
 }
 
+fn parse_ratio(matches: &getopts::Matches, name: &str) -> f32 {
+    match matches.opt_str(name) {
+        None => 0.0,
+        Some(ratio) => match ratio.parse() {
+            Ok(ratio) if (0.0..=1.0).contains(&ratio) => ratio,
+            _ => {
+                eprintln!("'{}' is not a ratio between 0 and 1!", ratio);
+                process::exit(ExitStatus::Usage as i32);
+            }
+        },
+    }
+}
+
 fn print_usage(program: &str, opts: &Options) {
-    let brief = format!("Usage: {} [options]", program);
+    let brief = format!(
+        "Usage: {} [options] [upload LOCAL REMOTE | download REMOTE LOCAL]\n       \
+         {} [options] job list | job status|wait|output|cancel ID\n       \
+         {} [options] history [list] | history show|rerun ID\n\n\
+         A download destination may contain {{node}}, replaced by each node name.\n\
+         Uploaded and downloaded files are limited to {} MiB.\n\
+         A command run with --submit goes on without the client, look it up with job.\n\
+         history rerun runs a past command again on the nodes where it failed.\n\n\
+         SELECTOR is made of node or group names, globs on node names (web-*),\n\
+         regexes on node names (/db[0-9]+/) and labels (role=db, dc=par*), combined\n\
+         with ! to exclude, & to intersect and , to unite, in that precedence, and\n\
+         parentheses: 'web-*&!dc=par1,role=db'. Quote it from the shell.\n\n\
+         Exit codes: 0 success, 1 non-zero exit status on some nodes, 2 transport\n\
+         failures or timeouts, 3 request rejected, 4 server unreachable, 5 usage error,\n\
+         6 local failure such as saving a download.\n\
+         --fail-policy fast stops waiting at the first node that doesn't succeed,\n\
+         it doesn't stop the command on the other nodes.",
+        program,
+        program,
+        program,
+        MAX_TRANSFER_SIZE / (1024 * 1024)
+    );
     print!("{}", opts.usage(&brief));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn cmd_status(node_name: &str, data: SshReturn) -> Response {
+        Response::CmdStatus(CmdReturn {
+            node_name: node_name.to_string(),
+            data,
+            duration: Duration::ZERO,
+        })
+    }
+
+    fn exited(exit_status: i32) -> SshReturn {
+        SshReturn::SshSuccess(SshSuccess {
+            stdout: None,
+            stderr: None,
+            exit_status,
+        })
+    }
+
+    /// Exit status of `policy` over `responses`, along with the number of
+    /// them recorded before it stopped waiting.
+    fn verdict(policy: FailPolicy, responses: &[Response]) -> (ExitStatus, usize) {
+        let mut verdict = Verdict::new(policy);
+        let mut recorded = 0;
+        for response in responses {
+            recorded += 1;
+            if verdict.record(response).is_break() {
+                break;
+            }
+        }
+
+        (verdict.exit_status(), recorded)
+    }
+
+    fn mixed() -> Vec<Response> {
+        vec![
+            cmd_status("web1", exited(0)),
+            cmd_status("web2", exited(3)),
+            cmd_status("web3", SshReturn::SshFailure("refused".to_string())),
+            cmd_status("web4", exited(0)),
+        ]
+    }
+
+    #[test]
+    fn any_policy_reports_the_worst_node() {
+        assert_eq!(
+            verdict(FailPolicy::Any, &mixed()),
+            (ExitStatus::Transport, 4)
+        );
+        assert_eq!(
+            verdict(FailPolicy::Any, &mixed()[..2]),
+            (ExitStatus::NonZero, 2)
+        );
+        assert_eq!(verdict(FailPolicy::Any, &[]), (ExitStatus::Success, 0));
+    }
+
+    #[test]
+    fn all_policy_fails_only_when_every_node_failed() {
+        assert_eq!(verdict(FailPolicy::All, &mixed()), (ExitStatus::Success, 4));
+        let failed = [
+            cmd_status("web2", exited(3)),
+            cmd_status("web3", SshReturn::SshTimeout(TimeoutStage::Exec, 5)),
+        ];
+        assert_eq!(verdict(FailPolicy::All, &failed), (ExitStatus::NonZero, 2));
+        assert_eq!(verdict(FailPolicy::All, &[]), (ExitStatus::Success, 0));
+    }
+
+    #[test]
+    fn fast_policy_stops_at_the_first_failure() {
+        assert_eq!(
+            verdict(FailPolicy::Fast, &mixed()),
+            (ExitStatus::NonZero, 2)
+        );
+        let succeeded = [cmd_status("web1", exited(0)), cmd_status("web4", exited(0))];
+        assert_eq!(
+            verdict(FailPolicy::Fast, &succeeded),
+            (ExitStatus::Success, 2)
+        );
+    }
+
+    #[test]
+    fn skipped_and_aborted_nodes_are_transport_failures() {
+        for data in [SshReturn::SshSkipped(0.5), SshReturn::SshAborted] {
+            let responses = [cmd_status("web1", exited(0)), cmd_status("web2", data)];
+            assert_eq!(
+                verdict(FailPolicy::Any, &responses),
+                (ExitStatus::Transport, 2)
+            );
+        }
+    }
+
+    #[test]
+    fn error_response_is_a_rejection_whatever_the_policy() {
+        let responses = [
+            cmd_status("web1", exited(0)),
+            Response::Error(ResponseError::Forbidden("no".to_string())),
+            cmd_status("web2", exited(0)),
+        ];
+        for policy in [FailPolicy::Any, FailPolicy::All, FailPolicy::Fast] {
+            assert_eq!(verdict(policy, &responses), (ExitStatus::Rejected, 2));
+        }
+    }
+
+    #[test]
+    fn errors_map_to_exit_codes() {
+        let io_error = || Error::Io(io::Error::from(io::ErrorKind::ConnectionRefused));
+        assert_eq!(ExitStatus::from(&io_error()), ExitStatus::Unreachable);
+        assert_eq!(
+            ExitStatus::from(&Error::RequestError(RequestError::UnsupportedCapability(
+                "jobs".to_string()
+            ))),
+            ExitStatus::Rejected
+        );
+        // Failing to save a download isn't the server's fault.
+        let disk_full = Error::Io(io::Error::other("disk full"));
+        assert_eq!(
+            ExitStatus::from(&Error::Local(Box::new(disk_full))),
+            ExitStatus::Local
+        );
+        assert_eq!(ExitStatus::Local as i32, 6);
+    }
+}
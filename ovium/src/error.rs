@@ -1,5 +1,7 @@
+use crate::types::{Auth, TimeoutStage};
 use std::fmt;
 use std::io;
+use std::path::PathBuf;
 
 #[derive(Debug)]
 pub enum Error {
@@ -9,6 +11,20 @@ pub enum Error {
     Bincode(Box<bincode::ErrorKind>),
     ConfigError(ConfigError),
     RequestError(RequestError),
+    Frame(FrameError),
+    Timeout(TimeoutStage, u64),
+    Auth(Auth, Box<Error>),
+    HostKey(HostKeyError),
+    Transfer(String),
+    Yaml(serde_yaml::Error),
+    Aborted,
+    History(redb::Error),
+    Inventory(String),
+    /// Failure on the way to a node, through the named jump host.
+    Jump(String, Box<Error>),
+    /// Failure on the client's own host while handling the server's
+    /// answers, e.g. saving a download or printing the results.
+    Local(Box<Error>),
 }
 
 #[derive(Debug)]
@@ -21,12 +37,55 @@ pub struct OviumError {
 #[derive(Debug)]
 pub enum ConfigError {
     UnknownNodes(Vec<String>),
-    Parse(toml::de::Error),
+    Parse(PathBuf, toml::de::Error),
+    InvalidSelector(String),
+    /// Groups leading from a group back to itself.
+    GroupCycle(Vec<String>),
+    InvalidNode {
+        node: String,
+        reason: String,
+    },
+    /// A node, group or section defined in two config files or inventory
+    /// sources.
+    Duplicate {
+        what: String,
+        first: String,
+        second: String,
+    },
 }
 
 #[derive(Debug)]
 pub enum RequestError {
     UnknownNodes(Vec<String>),
+    Invalid(String),
+    HandshakeRequired,
+    IncompatibleProtocol(u32),
+    UnsupportedCapability(String),
+    Forbidden(String),
+    UnknownJob(u64),
+    UnknownHistoryEntry(u64),
+}
+
+/// Why a node selector couldn't be resolved.
+#[derive(Debug)]
+pub enum SelectorError {
+    Syntax(String),
+    /// Names, patterns or labels that select no node.
+    Unknown(Vec<String>),
+}
+
+#[derive(Debug)]
+pub enum HostKeyError {
+    Mismatch { host: String, fingerprint: String },
+    Unknown { host: String, fingerprint: String },
+    Unavailable,
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    BadMagic(u8),
+    UnsupportedVersion(u8),
+    TooLarge(usize),
 }
 
 #[derive(Debug)]
@@ -36,6 +95,8 @@ pub enum ErrorKind {
     Handle,
     Bind,
     ClientRun,
+    Audit,
+    History,
 }
 
 impl fmt::Display for Error {
@@ -47,6 +108,19 @@ impl fmt::Display for Error {
             Error::Bincode(err) => write!(f, "Bincode error: {}", err),
             Error::ConfigError(err) => write!(f, "{}", err),
             Error::RequestError(err) => write!(f, "{}", err),
+            Error::Frame(err) => write!(f, "Frame error: {}", err),
+            Error::Timeout(stage, seconds) => {
+                write!(f, "{} timed out after {}s", stage, seconds)
+            }
+            Error::Auth(auth, err) => write!(f, "Authentication with {} failed: {}", auth, err),
+            Error::HostKey(err) => write!(f, "Host key verification failed: {}", err),
+            Error::Transfer(err) => write!(f, "Transfer error: {}", err),
+            Error::Yaml(err) => write!(f, "Yaml error: {}", err),
+            Error::Aborted => write!(f, "Aborted by a server shutdown or a job cancel"),
+            Error::History(err) => write!(f, "History error: {}", err),
+            Error::Inventory(err) => write!(f, "Inventory error: {}", err),
+            Error::Jump(host, err) => write!(f, "Through jump host {}: {}", host, err),
+            Error::Local(err) => write!(f, "{}", err),
         }
     }
 }
@@ -54,8 +128,20 @@ impl fmt::Display for Error {
 impl fmt::Display for ConfigError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            ConfigError::Parse(err) => write!(f, "Parsing error: {}", err),
+            ConfigError::Parse(file, err) => write!(f, "Parsing error in {:?}: {}", file, err),
+            ConfigError::Duplicate {
+                what,
+                first,
+                second,
+            } => write!(f, "{} is defined in both {} and {}", what, first, second),
             ConfigError::UnknownNodes(err) => write!(f, "Unknown nodes: '{}'", err.join(", ")),
+            ConfigError::InvalidSelector(reason) => write!(f, "Invalid node selector: {}", reason),
+            ConfigError::InvalidNode { node, reason } => {
+                write!(f, "Invalid node '{}': {}", node, reason)
+            }
+            ConfigError::GroupCycle(cycle) => {
+                write!(f, "Group contains itself: {}", cycle.join(" -> "))
+            }
         }
     }
 }
@@ -64,6 +150,61 @@ impl fmt::Display for RequestError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             RequestError::UnknownNodes(err) => write!(f, "Unknown nodes: '{}'", err.join(", ")),
+            RequestError::Invalid(reason) => write!(f, "Invalid request: {}", reason),
+            RequestError::Forbidden(reason) => write!(f, "Forbidden: {}", reason),
+            RequestError::UnknownJob(id) => write!(f, "Unknown job: {}", id),
+            RequestError::UnknownHistoryEntry(id) => write!(f, "Unknown history entry: {}", id),
+            RequestError::HandshakeRequired => {
+                write!(f, "First message of a connection must be a handshake")
+            }
+            RequestError::IncompatibleProtocol(version) => {
+                write!(f, "Incompatible protocol version: {}", version)
+            }
+            RequestError::UnsupportedCapability(capability) => {
+                write!(f, "Server doesn't support '{}'", capability)
+            }
+        }
+    }
+}
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SelectorError::Syntax(reason) => write!(f, "{}", reason),
+            SelectorError::Unknown(terms) => write!(f, "Unknown nodes: '{}'", terms.join(", ")),
+        }
+    }
+}
+
+impl fmt::Display for HostKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HostKeyError::Mismatch { host, fingerprint } => write!(
+                f,
+                "{} presented {}, which doesn't match its known_hosts entry",
+                host, fingerprint
+            ),
+            HostKeyError::Unknown { host, fingerprint } => {
+                write!(f, "{} ({}) isn't in known_hosts", host, fingerprint)
+            }
+            HostKeyError::Unavailable => write!(f, "unable to get the host key"),
+        }
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::BadMagic(byte) => write!(f, "bad magic byte: {:#04x}", byte),
+            FrameError::UnsupportedVersion(version) => {
+                write!(f, "unsupported frame version: {}", version)
+            }
+            FrameError::TooLarge(size) => write!(
+                f,
+                "frame of {} bytes exceeds the maximum of {} bytes",
+                size,
+                crate::types::MAX_FRAME_SIZE
+            ),
         }
     }
 }
@@ -76,6 +217,8 @@ impl fmt::Display for OviumError {
             ErrorKind::Handle => writeln!(f, "Handle error"),
             ErrorKind::Bind => writeln!(f, "Error while binding socket"),
             ErrorKind::ClientRun => writeln!(f, "Error running Ovium client"),
+            ErrorKind::Audit => writeln!(f, "Failed to open the audit log"),
+            ErrorKind::History => writeln!(f, "Failed to open the history"),
         }?;
 
         if let Some(detail) = &self.detail {
@@ -113,6 +256,18 @@ impl From<serde_json::Error> for Error {
     }
 }
 
+impl From<redb::Error> for Error {
+    fn from(error: redb::Error) -> Self {
+        Error::History(error)
+    }
+}
+
+impl From<serde_yaml::Error> for Error {
+    fn from(error: serde_yaml::Error) -> Self {
+        Error::Yaml(error)
+    }
+}
+
 impl From<Box<bincode::ErrorKind>> for Error {
     fn from(error: Box<bincode::ErrorKind>) -> Self {
         Error::Bincode(error)
@@ -131,6 +286,18 @@ impl From<RequestError> for Error {
     }
 }
 
+impl From<HostKeyError> for Error {
+    fn from(error: HostKeyError) -> Self {
+        Error::HostKey(error)
+    }
+}
+
+impl From<FrameError> for Error {
+    fn from(error: FrameError) -> Self {
+        Error::Frame(error)
+    }
+}
+
 impl From<(ErrorKind, Error)> for OviumError {
     fn from((kind, source): (ErrorKind, Error)) -> Self {
         OviumError {
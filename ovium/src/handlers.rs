@@ -1,124 +1,660 @@
-use crate::error::{Error, RequestError};
+use crate::acl::{may_access, Peer};
+use crate::audit::{AuditTrail, NodeOutcome};
+use crate::error::{Error, RequestError, SelectorError};
+use crate::history::HistoryRecorder;
+use crate::logging;
 use crate::server::*;
 use crate::types::*;
 use crossbeam_utils::thread;
 use log::{error, info, warn};
+use std::fs;
 use std::io::{BufWriter, Write};
-use std::sync::mpsc::{self, channel};
-use std::sync::Arc;
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::mpsc::{channel, Sender};
+use std::time::{Duration, Instant};
 
 impl ServerActions<CmdRequest> for ServerHandler<CmdRequest> {
-    fn handle(self, server_config: &ServerConfig) -> Result<(), Error> {
-        let mut nodes: Vec<&String> = Vec::new();
-        for node in &self.req.nodes {
-            if server_config.is_group(node) {
-                if let Some(members) = server_config.groups.get(node) {
-                    nodes.extend(members);
+    fn handle(self, server: &Server, server_config: &ServerConfig) -> Result<(), Error> {
+        let nodes = server_config.resolve_nodes(&self.req.nodes);
+
+        let req = &self.req;
+        let stream_output = req.stream;
+        // Can't use join() on Vec<&String>
+        // Might be a bug: https://github.com/rust-lang/rust/issues/82910
+        info!("Received command '{}' for nodes: {:?}", req.command, nodes);
+
+        let mut writer = BufWriter::new(&self.stream);
+        let mut results = Vec::new();
+        let trail = &self.trail;
+        let mut recorder = HistoryRecorder::new(self.id, &self.peer, req, &nodes, false);
+        let mut forward = |response: Response| -> Result<(), Error> {
+            recorder.record(&response);
+            if let Response::CmdStatus(cmd_return) = &response {
+                trail.record(NodeOutcome::from(cmd_return));
+            }
+            if stream_output {
+                writer.write_all(&response.encode()?)?;
+                writer.flush()?;
+            } else if let Response::CmdStatus(cmd_return) = response {
+                results.push(cmd_return);
+            }
+            Ok(())
+        };
+        let ran = run_on_nodes(
+            req,
+            &nodes,
+            server,
+            server_config,
+            &|| server.is_cancelled(),
+            &mut forward,
+        );
+        save_history(server, &recorder.finish());
+        ran?;
+
+        if !stream_output {
+            writer.write_all(&Response::Cmd(results).encode()?)?;
+        }
+
+        Ok(())
+    }
+
+    fn validate_request(&self, server_config: &ServerConfig) -> Result<(), Error> {
+        check_known_nodes(&self.stream, server_config, &self.req.nodes)?;
+        check_batch_size(&self.stream, &self.req)
+    }
+}
+
+impl ServerActions<SubmitRequest> for ServerHandler<SubmitRequest> {
+    fn handle(self, server: &Server, server_config: &ServerConfig) -> Result<(), Error> {
+        let req = &self.req.cmd;
+        let nodes = server_config.resolve_nodes(&req.nodes);
+        let job = server.jobs().submit(self.id, &self.peer, req, &nodes);
+        info!(
+            "Submitted '{}' as job {} for nodes: {:?}",
+            req.command, job.id, nodes
+        );
+
+        // The client only waits for the job id, the job goes on without it.
+        let told = Response::Job(job.info()).encode().and_then(|response| {
+            let mut writer = BufWriter::new(&self.stream);
+            writer.write_all(&response)?;
+            Ok(writer.flush()?)
+        });
+        if let Err(err) = told {
+            warn!("Failed to send job {} to the client: {}", job.id, err);
+        }
+        let _ = self.stream.shutdown(Shutdown::Both);
+
+        let trail = &self.trail;
+        let ran = run_on_nodes(
+            req,
+            &nodes,
+            server,
+            server_config,
+            &|| server.is_cancelled() || job.is_cancelled(),
+            |response| {
+                job.record(&response);
+                if let Response::CmdStatus(cmd_return) = &response {
+                    trail.record(NodeOutcome::from(cmd_return));
+                }
+                Ok(())
+            },
+        );
+        save_history(server, &job.finish());
+        info!("Job {} is over", job.id);
+
+        ran
+    }
+
+    fn validate_request(&self, server_config: &ServerConfig) -> Result<(), Error> {
+        check_known_nodes(&self.stream, server_config, &self.req.cmd.nodes)?;
+        check_batch_size(&self.stream, &self.req.cmd)
+    }
+}
+
+impl ServerActions<JobRequest> for ServerHandler<JobRequest> {
+    fn handle(self, server: &Server, server_config: &ServerConfig) -> Result<(), Error> {
+        let job = match self.req.id() {
+            Some(id) => match server.jobs().get(id) {
+                Some(job) if may_access(server_config, &self.peer, job.uid) => Some(job),
+                // Other users' jobs are none of the client's business.
+                _ => {
+                    let response = Response::Error(ResponseError::UnknownJob(id));
+                    let mut writer = BufWriter::new(&self.stream);
+                    writer.write_all(&response.encode()?)?;
+
+                    return Err(RequestError::UnknownJob(id).into());
                 }
-            } else {
-                nodes.push(node);
+            },
+            None => None,
+        };
+
+        let response = match (&self.req, job) {
+            (JobRequest::List, _) => Response::Jobs(
+                server
+                    .jobs()
+                    .list()
+                    .iter()
+                    .filter(|job| may_access(server_config, &self.peer, job.uid))
+                    .map(|job| job.info())
+                    .collect(),
+            ),
+            (JobRequest::Status(_), Some(job)) => Response::Job(job.info()),
+            (JobRequest::Wait(_), Some(job)) => Response::Cmd(job.wait()),
+            (JobRequest::Output(_), Some(job)) => Response::Cmd(job.results()),
+            (JobRequest::Cancel(_), Some(job)) => {
+                if !job.is_finished() {
+                    info!("Cancelling job {}", job.id);
+                    job.cancel();
+                }
+                Response::Job(job.info())
             }
+            (_, None) => unreachable!("every request but List has a job id"),
+        };
+
+        let mut writer = BufWriter::new(&self.stream);
+        writer.write_all(&response.encode()?)?;
+
+        Ok(())
+    }
+
+    fn validate_request(&self, _server_config: &ServerConfig) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ServerActions<HistoryRequest> for ServerHandler<HistoryRequest> {
+    fn handle(self, server: &Server, server_config: &ServerConfig) -> Result<(), Error> {
+        let response = match &self.req {
+            HistoryRequest::List(filter) => {
+                let nodes = server_config.resolve_nodes(&filter.nodes);
+                let entries = server
+                    .history()
+                    .list(filter, &nodes, |entry| {
+                        may_access(server_config, &self.peer, entry.uid)
+                    })?
+                    .into_iter()
+                    .map(|mut entry| {
+                        // Listing is about what ran where, `Show` has the output.
+                        for cmd_return in &mut entry.results {
+                            if let SshReturn::SshSuccess(success) = &mut cmd_return.data {
+                                success.stdout = None;
+                                success.stderr = None;
+                            }
+                        }
+                        entry
+                    })
+                    .collect();
+                Response::History(entries)
+            }
+            HistoryRequest::Show(id) => match server.history().get(*id)? {
+                Some(entry) if may_access(server_config, &self.peer, entry.uid) => {
+                    Response::HistoryEntry(entry)
+                }
+                _ => {
+                    let response = Response::Error(ResponseError::UnknownHistoryEntry(*id));
+                    let mut writer = BufWriter::new(&self.stream);
+                    writer.write_all(&response.encode()?)?;
+
+                    return Err(RequestError::UnknownHistoryEntry(*id).into());
+                }
+            },
+        };
+
+        let mut writer = BufWriter::new(&self.stream);
+        writer.write_all(&response.encode()?)?;
+
+        Ok(())
+    }
+
+    fn validate_request(&self, server_config: &ServerConfig) -> Result<(), Error> {
+        match &self.req {
+            HistoryRequest::List(filter) => {
+                check_known_nodes(&self.stream, server_config, &filter.nodes)
+            }
+            HistoryRequest::Show(_) => Ok(()),
         }
-        nodes.sort();
-        nodes.dedup();
+    }
+}
 
-        let cmd = &self.req.command;
-        let (tx, rx) = channel();
-        let nodes_nb = nodes.len();
-        // Can't use join() on Vec<&String>
-        // Might be a bug: https://github.com/rust-lang/rust/issues/82910
-        info!("Received command '{}' for nodes: {:?}", cmd, nodes);
-        let server_config = Arc::new(&server_config);
-
-        thread::scope(move |s| {
-            let mut threads = Vec::new();
-
-            for node_name in nodes {
-                let node_tx = tx.clone();
-                let node_cmd = cmd.clone();
-                let node_server_config = Arc::clone(&server_config);
-                let node_thread = s.spawn(move |_| -> Result<(), mpsc::SendError<_>> {
-                    info!("Launching '{}' on node: {}", node_cmd, node_name);
-                    let exec_return = Server::execute_cmd(
-                        &node_server_config.nodes[&node_name.to_owned()],
-                        node_cmd,
-                    );
-                    let ssh_return = match exec_return {
-                        Ok(ssh_return) => SshReturn::SshSuccess(ssh_return),
-                        Err(err) => SshReturn::SshFailure(err.to_string()),
-                    };
-                    let cmd_return = CmdReturn {
-                        node_name: node_name.clone(),
-                        data: ssh_return,
-                    };
-                    node_tx.send(cmd_return)?;
-                    Ok(())
+/// Turns `rerun` into a request running its history entry's command again
+/// on the nodes where it failed.
+pub fn rerun_request(
+    server: &Server,
+    stream: &UnixStream,
+    server_config: &ServerConfig,
+    peer: &Peer,
+    rerun: &RerunRequest,
+) -> Result<CmdRequest, Error> {
+    let (response, err) = match server.history().get(rerun.id)? {
+        Some(entry) if may_access(server_config, peer, entry.uid) => {
+            let nodes = entry.failed_nodes();
+            if !nodes.is_empty() {
+                info!(
+                    "Rerunning '{}' of request {} on nodes: {:?}",
+                    entry.command, entry.id, nodes
+                );
+                return Ok(CmdRequest {
+                    nodes,
+                    command: entry.command,
+                    stream: rerun.stream,
+                    timeouts: rerun.timeouts,
+                    parallelism: rerun.parallelism,
+                    batch: None,
                 });
+            }
+            let reason = format!("no node failed in request {}", rerun.id);
+            (
+                ResponseError::InvalidRequest(reason.clone()),
+                RequestError::Invalid(reason),
+            )
+        }
+        _ => (
+            ResponseError::UnknownHistoryEntry(rerun.id),
+            RequestError::UnknownHistoryEntry(rerun.id),
+        ),
+    };
 
-                threads.push(node_thread);
+    let mut writer = BufWriter::new(stream);
+    writer.write_all(&Response::Error(response).encode()?)?;
+    Err(err.into())
+}
+
+/// Keeps `entry` in the history. The request is over already, a failure is
+/// only logged.
+fn save_history(server: &Server, entry: &HistoryEntry) {
+    if let Err(err) = server.history().save(entry) {
+        error!(
+            "Failed to save request {} in the history: {}",
+            entry.id, err
+        );
+    }
+}
+
+/// Runs `req` on `nodes`, at most `parallelism` at a time and batch by batch
+/// when asked, handing every response to `forward` as it comes. Nodes not
+/// done by the time `cancelled` returns true are aborted.
+fn run_on_nodes<F>(
+    req: &CmdRequest,
+    nodes: &[&String],
+    server: &Server,
+    server_config: &ServerConfig,
+    cancelled: &(dyn Fn() -> bool + Sync),
+    mut forward: F,
+) -> Result<(), Error>
+where
+    F: FnMut(Response) -> Result<(), Error>,
+{
+    let parallelism = match req.parallelism {
+        Some(parallelism) => parallelism.limit(nodes.len()),
+        None => nodes.len(),
+    };
+    // Without a batch policy, the whole request is a single batch whose
+    // nodes are run at most `parallelism` at a time.
+    let batches: Vec<&[&String]> = match req.batch {
+        Some(_) => nodes.chunks(parallelism.max(1)).collect(),
+        None => vec![nodes],
+    };
+
+    let log_context = &logging::context();
+    let mut batches = batches.into_iter();
+    while let Some(batch) = batches.next() {
+        let failures = thread::scope(|s| -> Result<usize, Error> {
+            let (queue_tx, queue_rx) = crossbeam_channel::unbounded();
+            for node_name in batch {
+                queue_tx.send(*node_name).unwrap();
+            }
+            drop(queue_tx);
+
+            let (tx, rx) = channel();
+            for _ in 0..parallelism.min(batch.len()) {
+                let worker_queue = queue_rx.clone();
+                let worker_tx = tx.clone();
+                s.spawn(move |_| {
+                    for node_name in worker_queue {
+                        logging::set_context(log_context.with_node(node_name));
+                        run_on_node(node_name, req, server, server_config, cancelled, &worker_tx);
+                    }
+                });
             }
+            drop(tx);
 
-            // If node_tx.send should failed
-            for th in threads {
-                if let Err(err) = th.join().unwrap() {
-                    warn!("A command execution thread failed with error: {}", err);
+            // Every worker holds a sender, so this ends once they all
+            // returned.
+            let mut failures = 0;
+            for response in rx {
+                if let Response::CmdStatus(cmd_return) = &response {
+                    if !cmd_return.data.is_success() {
+                        failures += 1;
+                    }
                 }
+                forward(response)?;
             }
+
+            Ok(failures)
         })
-        .unwrap();
+        .unwrap()?;
 
-        let mut results = Vec::new();
-        for _ in 0..nodes_nb {
-            if let Ok(recv) = rx.recv() {
-                results.push(recv);
+        if let Some(policy) = req.batch {
+            let failure_ratio = failures as f32 / batch.len() as f32;
+            if failure_ratio > policy.max_failure_ratio {
+                warn!(
+                    "{}/{} nodes failed in the last batch, stopping '{}'",
+                    failures,
+                    batch.len(),
+                    req.command
+                );
+                for node_name in batches.flatten() {
+                    forward(Response::CmdStatus(CmdReturn {
+                        node_name: node_name.to_string(),
+                        data: SshReturn::SshSkipped(failure_ratio),
+                        duration: Duration::ZERO,
+                    }))?;
+                }
+                break;
             }
         }
+    }
 
-        let cmd_response = Response::Cmd(results);
+    Ok(())
+}
 
-        let mut writer = BufWriter::new(&self.stream);
-        writer.write_all(&cmd_response.encode()?)?;
+impl ServerActions<UploadRequest> for ServerHandler<UploadRequest> {
+    fn handle(self, server: &Server, server_config: &ServerConfig) -> Result<(), Error> {
+        let nodes = server_config.resolve_nodes(&self.req.nodes);
+        info!(
+            "Uploading {} bytes to '{}' on nodes: {:?}",
+            self.req.contents.len(),
+            self.req.destination,
+            nodes
+        );
 
-        Ok(())
+        let cancelled = || server.is_cancelled();
+        transfer_on_nodes(
+            &self.stream,
+            &self.trail,
+            nodes,
+            &cancelled,
+            |node_name, cancelled| {
+                let node = &server_config.nodes[node_name];
+                let settings = server_config.ssh_settings(node, self.req.timeouts);
+                server
+                    .upload(node_name, node, &self.req, &settings, cancelled)
+                    .map(TransferResult::Uploaded)
+            },
+        )
     }
 
     fn validate_request(&self, server_config: &ServerConfig) -> Result<(), Error> {
-        let available_names = &mut server_config
-            .nodes
-            .keys()
-            .into_iter()
-            .collect::<Vec<&String>>();
-
-        available_names.extend(
-            server_config
-                .groups
-                .keys()
-                .into_iter()
-                .collect::<Vec<&String>>(),
-        );
+        check_known_nodes(&self.stream, server_config, &self.req.nodes)
+    }
+}
+
+impl ServerActions<DownloadRequest> for ServerHandler<DownloadRequest> {
+    fn handle(self, server: &Server, server_config: &ServerConfig) -> Result<(), Error> {
+        let nodes = server_config.resolve_nodes(&self.req.nodes);
+        info!("Downloading '{}' from nodes: {:?}", self.req.source, nodes);
+
+        let cancelled = || server.is_cancelled();
+        transfer_on_nodes(
+            &self.stream,
+            &self.trail,
+            nodes,
+            &cancelled,
+            |node_name, cancelled| {
+                let node = &server_config.nodes[node_name];
+                let settings = server_config.ssh_settings(node, self.req.timeouts);
+                let contents =
+                    server.download(node_name, node, &self.req.source, &settings, cancelled)?;
+                Ok(TransferResult::Downloaded {
+                    path: self.req.destination.replace("{node}", node_name),
+                    contents,
+                })
+            },
+        )
+    }
+
+    fn validate_request(&self, server_config: &ServerConfig) -> Result<(), Error> {
+        check_known_nodes(&self.stream, server_config, &self.req.nodes)?;
+
+        let nodes = server_config.resolve_nodes(&self.req.nodes);
+        if nodes.len() > 1 && !self.req.destination.contains("{node}") {
+            let reason = format!(
+                "downloading from {} nodes needs a destination containing {{node}}",
+                nodes.len()
+            );
+            error!("{}", reason);
+            let error_response = Response::Error(ResponseError::InvalidRequest(reason.clone()));
+            let mut writer = BufWriter::new(&self.stream);
+            writer.write_all(&error_response.encode()?)?;
 
-        let not_in_config: Vec<String> = self
-            .req
-            .nodes
-            .iter()
-            .cloned()
-            .filter(|n| !available_names.contains(&n))
-            .collect();
+            return Err(Error::from(RequestError::Invalid(reason)));
+        }
 
-        if !not_in_config.is_empty() {
+        Ok(())
+    }
+}
+
+/// Tells the client about the node selectors that are invalid or select
+/// nodes or groups that aren't in the config.
+fn check_known_nodes(
+    stream: &UnixStream,
+    server_config: &ServerConfig,
+    selectors: &[String],
+) -> Result<(), Error> {
+    let (response, err) = match server_config.select_nodes(selectors) {
+        Ok(_) => return Ok(()),
+        Err(SelectorError::Unknown(not_in_config)) => {
             error!(
                 "Some nodes or groups are unknown (not in config): [{}]",
                 not_in_config.join(", ")
             );
+            (
+                ResponseError::UnknownNodes(not_in_config.clone()),
+                RequestError::UnknownNodes(not_in_config),
+            )
+        }
+        Err(SelectorError::Syntax(reason)) => {
+            error!("Invalid node selector: {}", reason);
+            (
+                ResponseError::InvalidRequest(reason.clone()),
+                RequestError::Invalid(reason),
+            )
+        }
+    };
 
-            let error_response =
-                Response::Error(ResponseError::UnknownNodes(not_in_config.clone()));
-            let mut writer = BufWriter::new(&self.stream);
-            writer.write_all(&error_response.encode()?)?;
+    let error_response = Response::Error(response);
+    let mut writer = BufWriter::new(stream);
+    writer.write_all(&error_response.encode()?)?;
+
+    Err(Error::from(err))
+}
+
+/// Rejects a batch rollout without a parallelism, which would make the
+/// whole request a single batch.
+fn check_batch_size(stream: &UnixStream, req: &CmdRequest) -> Result<(), Error> {
+    if req.batch.is_none() || req.parallelism.is_some() {
+        return Ok(());
+    }
+
+    let reason = "a batch rollout needs a parallelism as its batch size".to_string();
+    error!("{}", reason);
+    let error_response = Response::Error(ResponseError::InvalidRequest(reason.clone()));
+    let mut writer = BufWriter::new(stream);
+    writer.write_all(&error_response.encode()?)?;
+
+    Err(Error::from(RequestError::Invalid(reason)))
+}
+
+/// Runs `transfer` on every node at once, sending each node's result to the
+/// client as soon as it's known. `transfer` is handed `cancelled`, and
+/// aborts once it returns true.
+fn transfer_on_nodes<F>(
+    stream: &UnixStream,
+    trail: &AuditTrail,
+    nodes: Vec<&String>,
+    cancelled: &(dyn Fn() -> bool + Sync),
+    transfer: F,
+) -> Result<(), Error>
+where
+    F: Fn(&str, &dyn Fn() -> bool) -> Result<TransferResult, Error> + Sync,
+{
+    let transfer = &transfer;
+    let log_context = &logging::context();
+    thread::scope(|s| -> Result<(), Error> {
+        let (tx, rx) = channel();
+        for node_name in nodes {
+            let node_tx = tx.clone();
+            s.spawn(move |_| {
+                logging::set_context(log_context.with_node(node_name));
+                let transferred = if cancelled() {
+                    Err(Error::Aborted)
+                } else {
+                    transfer(node_name, cancelled)
+                };
+                let data = match transferred {
+                    Ok(data) => data,
+                    Err(Error::Timeout(stage, seconds)) => {
+                        TransferResult::SshTimeout(stage, seconds)
+                    }
+                    Err(err) => TransferResult::SshFailure(err.to_string()),
+                };
+                let transfer_return = TransferReturn {
+                    node_name: node_name.clone(),
+                    data,
+                };
+                if let Err(err) = node_tx.send(Response::Transfer(transfer_return)) {
+                    warn!("A transfer thread failed with error: {}", err);
+                }
+            });
+        }
+        drop(tx);
+
+        let mut writer = BufWriter::new(stream);
+        for response in rx {
+            if let Response::Transfer(transfer_return) = &response {
+                trail.record(NodeOutcome::from(transfer_return));
+            }
+            writer.write_all(&response.encode()?)?;
+            writer.flush()?;
+        }
+
+        Ok(())
+    })
+    .unwrap()
+}
 
-            return Err(Error::from(RequestError::UnknownNodes(not_in_config)));
+/// Runs the request's command on `node_name` and sends its output (when
+/// streaming) and final status through `tx`.
+fn run_on_node(
+    node_name: &str,
+    req: &CmdRequest,
+    server: &Server,
+    server_config: &ServerConfig,
+    cancelled: &dyn Fn() -> bool,
+    tx: &Sender<Response>,
+) {
+    // Nodes still queued when the request is cancelled aren't started at all.
+    if cancelled() {
+        let cmd_return = CmdReturn {
+            node_name: node_name.to_string(),
+            data: SshReturn::SshAborted,
+            duration: Duration::ZERO,
+        };
+        if let Err(err) = tx.send(Response::CmdStatus(cmd_return)) {
+            warn!("A command execution thread failed with error: {}", err);
         }
+        return;
+    }
+
+    info!("Launching '{}' on node: {}", req.command, node_name);
+    let mut send_output = |kind, data: String| {
+        let cmd_output = CmdOutput {
+            node_name: node_name.to_string(),
+            kind,
+            data,
+        };
+        // The client is gone if this fails, the final status send below
+        // reports it.
+        let _ = tx.send(Response::CmdOutput(cmd_output));
+    };
+    let output: Option<&mut dyn FnMut(OutputKind, String)> = if req.stream {
+        Some(&mut send_output)
+    } else {
+        None
+    };
+    let node = &server_config.nodes[node_name];
+    let settings = server_config.ssh_settings(node, req.timeouts);
+    let started = Instant::now();
+    let exec_return =
+        server.execute_cmd(node_name, node, &req.command, &settings, cancelled, output);
+    let ssh_return = match exec_return {
+        Ok(ssh_return) => SshReturn::SshSuccess(ssh_return),
+        Err(Error::Timeout(stage, seconds)) => {
+            warn!(
+                "'{}' on node {}: {} timed out",
+                req.command, node_name, stage
+            );
+            SshReturn::SshTimeout(stage, seconds)
+        }
+        Err(Error::Aborted) => {
+            warn!("'{}' on node {}: aborted", req.command, node_name);
+            SshReturn::SshAborted
+        }
+        Err(err) => SshReturn::SshFailure(err.to_string()),
+    };
+    let cmd_return = CmdReturn {
+        node_name: node_name.to_string(),
+        data: ssh_return,
+        duration: started.elapsed(),
+    };
+    if let Err(err) = tx.send(Response::CmdStatus(cmd_return)) {
+        warn!("A command execution thread failed with error: {}", err);
+    }
+}
+
+impl ServerActions<StatusRequest> for ServerHandler<StatusRequest> {
+    fn handle(self, server: &Server, _server_config: &ServerConfig) -> Result<(), Error> {
+        let status = ServerStatus {
+            pool: server.pool().status(),
+        };
+
+        let mut writer = BufWriter::new(&self.stream);
+        writer.write_all(&Response::Status(status).encode()?)?;
+
+        Ok(())
+    }
 
+    fn validate_request(&self, _server_config: &ServerConfig) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ServerActions<ReloadRequest> for ServerHandler<ReloadRequest> {
+    fn handle(self, server: &Server, _server_config: &ServerConfig) -> Result<(), Error> {
+        let response = match server.reload() {
+            Ok(config) => Response::Reload(ReloadReport {
+                nodes: config.nodes.len(),
+                groups: config.groups.len(),
+            }),
+            Err(err) => {
+                error!(
+                    "Configuration reload failed, keeping the current one: {}",
+                    err
+                );
+                Response::Error(ResponseError::InvalidConfig(err.to_string()))
+            }
+        };
+
+        let mut writer = BufWriter::new(&self.stream);
+        writer.write_all(&response.encode()?)?;
+
+        Ok(())
+    }
+
+    fn validate_request(&self, _server_config: &ServerConfig) -> Result<(), Error> {
         Ok(())
     }
 }
@@ -128,6 +664,22 @@ impl ClientActions<Response> for ClientHandler<Response> {
         match self.response {
             Response::Cmd(inner_resp) => ClientHandler::<Vec<CmdReturn>>::new(inner_resp).handle(),
             Response::Error(inner_resp) => ClientHandler::<ResponseError>::new(inner_resp).handle(),
+            Response::CmdOutput(inner_resp) => ClientHandler::<CmdOutput>::new(inner_resp).handle(),
+            Response::CmdStatus(inner_resp) => ClientHandler::<CmdReturn>::new(inner_resp).handle(),
+            Response::Hello(_) => Ok(()),
+            Response::Status(inner_resp) => ClientHandler::<ServerStatus>::new(inner_resp).handle(),
+            Response::Transfer(inner_resp) => {
+                ClientHandler::<TransferReturn>::new(inner_resp).handle()
+            }
+            Response::Reload(inner_resp) => ClientHandler::<ReloadReport>::new(inner_resp).handle(),
+            Response::Job(inner_resp) => ClientHandler::<JobInfo>::new(inner_resp).handle(),
+            Response::Jobs(inner_resp) => ClientHandler::<Vec<JobInfo>>::new(inner_resp).handle(),
+            Response::History(inner_resp) => {
+                ClientHandler::<Vec<HistoryEntry>>::new(inner_resp).handle()
+            }
+            Response::HistoryEntry(inner_resp) => {
+                ClientHandler::<HistoryEntry>::new(inner_resp).handle()
+            }
         }
     }
 }
@@ -142,6 +694,116 @@ impl ClientActions<Vec<CmdReturn>> for ClientHandler<Vec<CmdReturn>> {
     }
 }
 
+impl ClientActions<CmdOutput> for ClientHandler<CmdOutput> {
+    fn handle(self) -> Result<(), Error> {
+        match self.response.kind {
+            OutputKind::Stdout => println!("{}", self.response),
+            OutputKind::Stderr => eprintln!("{}", self.response),
+        }
+
+        Ok(())
+    }
+}
+
+impl ClientActions<CmdReturn> for ClientHandler<CmdReturn> {
+    fn handle(self) -> Result<(), Error> {
+        println!("{}", self.response);
+
+        Ok(())
+    }
+}
+
+impl ClientActions<TransferReturn> for ClientHandler<TransferReturn> {
+    fn handle(self) -> Result<(), Error> {
+        println!("{}", self.response);
+
+        Ok(())
+    }
+}
+
+/// Writes a downloaded file to `destination`, with `{node}` replaced by the
+/// node name, and records that path in `transfer_return`. The path the
+/// server sent is not trusted, nor is a node name that could lead out of
+/// `destination`.
+pub fn save_download(destination: &str, transfer_return: &mut TransferReturn) -> Result<(), Error> {
+    let node_name = &transfer_return.node_name;
+    if let TransferResult::Downloaded { path, contents } = &mut transfer_return.data {
+        if node_name.contains('/') || node_name.contains("..") {
+            return Err(Error::Transfer(format!(
+                "refusing to save the download of node '{}' under {}",
+                node_name, destination
+            )));
+        }
+        *path = destination.replace("{node}", node_name);
+
+        let path = Path::new(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, contents)?;
+    }
+
+    Ok(())
+}
+
+impl ClientActions<ServerStatus> for ClientHandler<ServerStatus> {
+    fn handle(self) -> Result<(), Error> {
+        println!("{}", self.response);
+
+        Ok(())
+    }
+}
+
+impl ClientActions<ReloadReport> for ClientHandler<ReloadReport> {
+    fn handle(self) -> Result<(), Error> {
+        println!("{}", self.response);
+
+        Ok(())
+    }
+}
+
+impl ClientActions<JobInfo> for ClientHandler<JobInfo> {
+    fn handle(self) -> Result<(), Error> {
+        println!("{}", self.response);
+
+        Ok(())
+    }
+}
+
+impl ClientActions<Vec<JobInfo>> for ClientHandler<Vec<JobInfo>> {
+    fn handle(self) -> Result<(), Error> {
+        if self.response.is_empty() {
+            println!("No jobs");
+        }
+        for job in self.response {
+            println!("{}", job);
+        }
+
+        Ok(())
+    }
+}
+
+impl ClientActions<Vec<HistoryEntry>> for ClientHandler<Vec<HistoryEntry>> {
+    fn handle(self) -> Result<(), Error> {
+        if self.response.is_empty() {
+            println!("No history entries");
+        }
+        for entry in self.response {
+            println!("{}", entry);
+        }
+
+        Ok(())
+    }
+}
+
+impl ClientActions<HistoryEntry> for ClientHandler<HistoryEntry> {
+    fn handle(self) -> Result<(), Error> {
+        println!("{:#}", self.response);
+
+        Ok(())
+    }
+}
+
 impl ClientActions<ResponseError> for ClientHandler<ResponseError> {
     fn handle(self) -> Result<(), Error> {
         println!("{}", &self.response);
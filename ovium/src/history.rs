@@ -0,0 +1,354 @@
+use crate::acl::Peer;
+use crate::audit::now;
+use crate::types::*;
+use redb::{Database, ReadableTable, TableDefinition};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// History entries by request id, as JSON.
+const ENTRIES: TableDefinition<u64, &[u8]> = TableDefinition::new("entries");
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryConfig {
+    /// Database the command requests and their results are kept in,
+    /// relative to the config directory. No history is kept when unset.
+    pub path: Option<PathBuf>,
+    /// Number of entries kept, the oldest ones are dropped first.
+    #[serde(default = "default_max_entries")]
+    pub max_entries: u64,
+    /// Days an entry is kept for.
+    #[serde(default = "default_max_age")]
+    pub max_age: u64,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            path: None,
+            max_entries: default_max_entries(),
+            max_age: default_max_age(),
+        }
+    }
+}
+
+fn default_max_entries() -> u64 {
+    1000
+}
+
+fn default_max_age() -> u64 {
+    30
+}
+
+/// Builds the `HistoryEntry` of a command request from its responses.
+#[derive(Debug)]
+pub struct HistoryRecorder {
+    entry: HistoryEntry,
+    /// Output streamed by each node, until its final status comes in.
+    streamed: HashMap<String, (String, String)>,
+}
+
+impl HistoryRecorder {
+    pub fn new(
+        id: u64,
+        peer: &Peer,
+        req: &CmdRequest,
+        nodes: &[&String],
+        job: bool,
+    ) -> HistoryRecorder {
+        HistoryRecorder {
+            entry: HistoryEntry {
+                id,
+                command: req.command.clone(),
+                targets: req.nodes.clone(),
+                nodes: nodes.iter().map(|node| node.to_string()).collect(),
+                uid: peer.uid,
+                user: peer.user.clone(),
+                job,
+                started_at: now(),
+                ended_at: 0.0,
+                results: Vec::new(),
+            },
+            streamed: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, response: &Response) {
+        match response {
+            Response::CmdOutput(output) => {
+                let (stdout, stderr) = self.streamed.entry(output.node_name.clone()).or_default();
+                match output.kind {
+                    OutputKind::Stdout => stdout.push_str(&output.data),
+                    OutputKind::Stderr => stderr.push_str(&output.data),
+                }
+            }
+            Response::CmdStatus(cmd_return) => {
+                let mut cmd_return = cmd_return.clone();
+                if let (Some((stdout, stderr)), SshReturn::SshSuccess(success)) = (
+                    self.streamed.remove(&cmd_return.node_name),
+                    &mut cmd_return.data,
+                ) {
+                    success.stdout = success.stdout.take().or(Some(stdout));
+                    success.stderr = success.stderr.take().or(Some(stderr));
+                }
+                self.entry.results.push(cmd_return);
+            }
+            _ => (),
+        }
+    }
+
+    /// The entry as recorded so far.
+    pub fn entry(&self) -> &HistoryEntry {
+        &self.entry
+    }
+
+    pub fn finish(&mut self) -> HistoryEntry {
+        self.entry.ended_at = now();
+        self.entry.clone()
+    }
+}
+
+impl HistoryFilter {
+    /// Whether `entry` matches, `nodes` being the nodes the filter's nodes and
+    /// groups resolve to.
+    fn matches(&self, entry: &HistoryEntry, nodes: &[&String]) -> bool {
+        (self.nodes.is_empty() || entry.nodes.iter().any(|node| nodes.contains(&node)))
+            && (!self.failed || !entry.failed_nodes().is_empty())
+            && self.exit_code.is_none_or(|exit_code| {
+                entry.results.iter().any(|cmd_return| {
+                    matches!(&cmd_return.data, SshReturn::SshSuccess(success) if success.exit_status == exit_code)
+                })
+            })
+            && self.since.is_none_or(|since| entry.started_at >= since)
+            && self.until.is_none_or(|until| entry.started_at <= until)
+    }
+}
+
+/// On-disk history of the command requests, pruned by count and age.
+pub struct History {
+    inner: Mutex<HistoryDb>,
+}
+
+struct HistoryDb {
+    config: HistoryConfig,
+    db: Option<Database>,
+}
+
+/// Settings of a reloaded configuration, ready to be applied.
+pub struct PreparedHistory {
+    config: HistoryConfig,
+    /// The reopened database, when it moved.
+    db: Option<HistoryDb>,
+}
+
+impl History {
+    pub fn new(config: &HistoryConfig) -> Result<History, redb::Error> {
+        Ok(History {
+            inner: Mutex::new(HistoryDb::open(config)?),
+        })
+    }
+
+    /// Gets the settings of a reloaded configuration ready, opening the
+    /// database if it moved. Nothing changes until they're applied.
+    pub fn prepare(&self, config: &HistoryConfig) -> Result<PreparedHistory, redb::Error> {
+        let inner = self.inner.lock().unwrap();
+        let db = if inner.config.path != config.path {
+            Some(HistoryDb::open(config)?)
+        } else {
+            None
+        };
+
+        Ok(PreparedHistory {
+            config: config.clone(),
+            db,
+        })
+    }
+
+    /// Swaps in settings from `prepare`.
+    pub fn apply(&self, prepared: PreparedHistory) {
+        let mut inner = self.inner.lock().unwrap();
+        match prepared.db {
+            Some(db) => *inner = db,
+            None => inner.config = prepared.config,
+        }
+    }
+
+    /// Id of the newest entry, so that ids keep growing across restarts.
+    pub fn last_id(&self) -> Result<u64, redb::Error> {
+        let inner = self.inner.lock().unwrap();
+        let db = match &inner.db {
+            Some(db) => db,
+            None => return Ok(0),
+        };
+        let txn = db.begin_read()?;
+        let table = txn.open_table(ENTRIES)?;
+        let last = table.last()?.map(|(id, _)| id.value()).unwrap_or(0);
+
+        Ok(last)
+    }
+
+    /// Adds `entry`, then drops the entries past the retention limits.
+    pub fn save(&self, entry: &HistoryEntry) -> Result<(), redb::Error> {
+        let inner = self.inner.lock().unwrap();
+        let db = match &inner.db {
+            Some(db) => db,
+            None => return Ok(()),
+        };
+        let value = serde_json::to_vec(entry).map_err(|err| redb::Error::Io(err.into()))?;
+        let oldest = now() - (inner.config.max_age * 24 * 60 * 60) as f64;
+
+        let txn = db.begin_write()?;
+        {
+            let mut table = txn.open_table(ENTRIES)?;
+            table.insert(entry.id, value.as_slice())?;
+            while table.len()? > inner.config.max_entries {
+                table.pop_first()?;
+            }
+            // Ids grow with time, the expired entries are the first ones.
+            loop {
+                let expired = match table.first()? {
+                    Some((_, value)) => !matches!(
+                        serde_json::from_slice::<HistoryEntry>(value.value()),
+                        Ok(first) if first.started_at >= oldest
+                    ),
+                    None => false,
+                };
+                if !expired {
+                    break;
+                }
+                table.pop_first()?;
+            }
+        }
+        txn.commit()?;
+
+        Ok(())
+    }
+
+    pub fn get(&self, id: u64) -> Result<Option<HistoryEntry>, redb::Error> {
+        let inner = self.inner.lock().unwrap();
+        let db = match &inner.db {
+            Some(db) => db,
+            None => return Ok(None),
+        };
+        let txn = db.begin_read()?;
+        let table = txn.open_table(ENTRIES)?;
+        let entry = table
+            .get(id)?
+            .and_then(|value| serde_json::from_slice(value.value()).ok());
+
+        Ok(entry)
+    }
+
+    /// Entries matching `filter` for which `visible` holds, newest first,
+    /// `nodes` being what its nodes and groups resolve to. Entries that
+    /// aren't visible don't count toward the limit.
+    pub fn list(
+        &self,
+        filter: &HistoryFilter,
+        nodes: &[&String],
+        visible: impl Fn(&HistoryEntry) -> bool,
+    ) -> Result<Vec<HistoryEntry>, redb::Error> {
+        let inner = self.inner.lock().unwrap();
+        let db = match &inner.db {
+            Some(db) => db,
+            None => return Ok(Vec::new()),
+        };
+        let txn = db.begin_read()?;
+        let table = txn.open_table(ENTRIES)?;
+
+        let mut entries = Vec::new();
+        for row in table.iter()?.rev() {
+            let (_, value) = row?;
+            let entry: HistoryEntry = match serde_json::from_slice(value.value()) {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            if filter.matches(&entry, nodes) && visible(&entry) {
+                entries.push(entry);
+            }
+            if filter.limit.is_some_and(|limit| entries.len() >= limit) {
+                break;
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+impl HistoryDb {
+    fn open(config: &HistoryConfig) -> Result<HistoryDb, redb::Error> {
+        let db = match &config.path {
+            Some(path) => {
+                let db = Database::create(path)?;
+                // Readers can't open a table that was never created.
+                let txn = db.begin_write()?;
+                txn.open_table(ENTRIES)?;
+                txn.commit()?;
+                Some(db)
+            }
+            None => None,
+        };
+
+        Ok(HistoryDb {
+            config: config.clone(),
+            db,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn entry(id: u64, uid: u32) -> HistoryEntry {
+        HistoryEntry {
+            id,
+            command: "uptime".to_string(),
+            targets: vec!["web1".to_string()],
+            nodes: vec!["web1".to_string()],
+            uid,
+            user: None,
+            job: false,
+            started_at: now(),
+            ended_at: now(),
+            results: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn limit_counts_only_visible_entries() {
+        let path = std::env::temp_dir().join(format!("ovium-history-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let history = History::new(&HistoryConfig {
+            path: Some(path.clone()),
+            ..HistoryConfig::default()
+        })
+        .unwrap();
+        // The other user ran the most recent commands.
+        for (id, uid) in [(1, 1000), (2, 1001), (3, 1000), (4, 1001), (5, 1001)] {
+            history.save(&entry(id, uid)).unwrap();
+        }
+
+        let filter = HistoryFilter {
+            limit: Some(2),
+            ..HistoryFilter::default()
+        };
+        let ids = |uid: u32| -> Vec<u64> {
+            history
+                .list(&filter, &[], |entry| entry.uid == uid)
+                .unwrap()
+                .iter()
+                .map(|entry| entry.id)
+                .collect()
+        };
+        assert_eq!(ids(1000), [3, 1]);
+        assert_eq!(ids(1001), [5, 4]);
+        assert!(ids(0).is_empty());
+        assert_eq!(history.list(&filter, &[], |_| true).unwrap().len(), 2);
+
+        drop(history);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,572 @@
+use crate::error::{ConfigError, Error};
+use crate::types::Node;
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// `[[inventory]]` entry of the config, a source of nodes and groups besides
+/// the config files.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "RawInventoryConfig")]
+pub struct InventoryConfig {
+    /// The `source` kind and the rest of the entry, the provider is built from.
+    kind: String,
+    settings: toml::value::Table,
+    pub provider: Box<dyn InventoryProvider>,
+    /// Seconds the nodes of the source are kept before reading it again.
+    pub refresh: u64,
+    /// Seconds the script or the endpoint get to answer.
+    pub timeout: u64,
+}
+
+#[derive(Deserialize)]
+struct RawInventoryConfig {
+    source: String,
+    #[serde(default = "default_refresh")]
+    refresh: u64,
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    #[serde(flatten)]
+    settings: toml::value::Table,
+}
+
+fn default_refresh() -> u64 {
+    300
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+/// Nodes and groups read from a source.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct InventoryData {
+    #[serde(default)]
+    pub nodes: HashMap<String, Node>,
+    #[serde(default)]
+    pub groups: HashMap<String, Vec<String>>,
+}
+
+/// A source of nodes and groups, yielding them shaped like in the config
+/// files. New sources implement it and get an entry in `PROVIDERS`.
+pub trait InventoryProvider: fmt::Display + fmt::Debug + Send + Sync {
+    /// Reads the source, giving up after `timeout`.
+    fn fetch(&self, timeout: Duration) -> Result<InventoryData, Error>;
+
+    /// Makes the paths of the source relative to the config directory.
+    fn resolve_paths(&mut self, _config_dir: &Path) {}
+
+    fn clone_box(&self) -> Box<dyn InventoryProvider>;
+}
+
+impl Clone for Box<dyn InventoryProvider> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+type BuildProvider = fn(toml::Value) -> Result<Box<dyn InventoryProvider>, toml::de::Error>;
+
+/// The `source` values of `[[inventory]]` entries, and their providers.
+const PROVIDERS: &[(&str, BuildProvider)] = &[
+    ("static", build::<StaticSource>),
+    ("script", build::<ScriptSource>),
+    ("http", build::<HttpSource>),
+];
+
+fn build<P>(settings: toml::Value) -> Result<Box<dyn InventoryProvider>, toml::de::Error>
+where
+    P: InventoryProvider + DeserializeOwned + 'static,
+{
+    Ok(Box::new(settings.try_into::<P>()?))
+}
+
+impl TryFrom<RawInventoryConfig> for InventoryConfig {
+    type Error = String;
+
+    fn try_from(raw: RawInventoryConfig) -> Result<Self, Self::Error> {
+        let (_, build) = PROVIDERS
+            .iter()
+            .find(|(kind, _)| *kind == raw.source)
+            .ok_or_else(|| format!("unknown inventory source '{}'", raw.source))?;
+        let provider = build(toml::Value::Table(raw.settings.clone()))
+            .map_err(|err| format!("{} inventory source: {}", raw.source, err))?;
+
+        Ok(InventoryConfig {
+            kind: raw.source,
+            settings: raw.settings,
+            provider,
+            refresh: raw.refresh,
+            timeout: raw.timeout,
+        })
+    }
+}
+
+/// Entries are the same if they were written the same, their providers are
+/// built from that.
+impl PartialEq for InventoryConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+            && self.settings == other.settings
+            && self.refresh == other.refresh
+            && self.timeout == other.timeout
+    }
+}
+
+impl InventoryConfig {
+    pub fn resolve_paths(&mut self, config_dir: &Path) {
+        self.provider.resolve_paths(config_dir);
+    }
+
+    fn fetch(&self) -> Result<InventoryData, Error> {
+        self.provider.fetch(Duration::from_secs(self.timeout))
+    }
+}
+
+/// A TOML file, relative to the config directory.
+#[derive(Deserialize, Debug, Clone)]
+pub struct StaticSource {
+    path: PathBuf,
+}
+
+impl InventoryProvider for StaticSource {
+    fn fetch(&self, _timeout: Duration) -> Result<InventoryData, Error> {
+        let content = fs::read_to_string(&self.path)?;
+        toml::from_str(&content).map_err(|err| ConfigError::Parse(self.path.clone(), err).into())
+    }
+
+    fn resolve_paths(&mut self, config_dir: &Path) {
+        self.path = config_dir.join(&self.path);
+    }
+
+    fn clone_box(&self) -> Box<dyn InventoryProvider> {
+        Box::new(self.clone())
+    }
+}
+
+impl fmt::Display for StaticSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "inventory file {:?}", self.path)
+    }
+}
+
+/// An executable printing JSON, relative to the config directory.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ScriptSource {
+    command: PathBuf,
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+impl InventoryProvider for ScriptSource {
+    fn fetch(&self, timeout: Duration) -> Result<InventoryData, Error> {
+        Ok(serde_json::from_slice(&run_script(
+            &self.command,
+            &self.args,
+            timeout,
+        )?)?)
+    }
+
+    fn resolve_paths(&mut self, config_dir: &Path) {
+        self.command = config_dir.join(&self.command);
+    }
+
+    fn clone_box(&self) -> Box<dyn InventoryProvider> {
+        Box::new(self.clone())
+    }
+}
+
+impl fmt::Display for ScriptSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "inventory script {:?}", self.command)
+    }
+}
+
+/// A plain HTTP endpoint answering GET requests with JSON.
+#[derive(Deserialize, Debug, Clone)]
+pub struct HttpSource {
+    url: String,
+}
+
+impl InventoryProvider for HttpSource {
+    fn fetch(&self, timeout: Duration) -> Result<InventoryData, Error> {
+        Ok(serde_json::from_slice(&http_get(&self.url, timeout)?)?)
+    }
+
+    fn clone_box(&self) -> Box<dyn InventoryProvider> {
+        Box::new(self.clone())
+    }
+}
+
+impl fmt::Display for HttpSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "inventory endpoint {}", self.url)
+    }
+}
+
+/// The inventory sources of the config, along with what they last yielded.
+#[derive(Default)]
+pub struct Inventory {
+    sources: Mutex<Vec<CachedSource>>,
+}
+
+#[derive(Clone)]
+struct CachedSource {
+    config: InventoryConfig,
+    /// When the source was last read, successfully or not.
+    checked: Option<Instant>,
+    /// What it last yielded.
+    data: Option<InventoryData>,
+}
+
+impl Inventory {
+    /// An inventory reading `configs`, where the sources this one already
+    /// reads start with their cached data.
+    pub fn with_sources(&self, configs: &[InventoryConfig]) -> Inventory {
+        let sources = self.sources.lock().unwrap();
+        let staged = configs
+            .iter()
+            .map(
+                |config| match sources.iter().find(|source| &source.config == config) {
+                    Some(source) => source.clone(),
+                    None => CachedSource {
+                        config: config.clone(),
+                        checked: None,
+                        data: None,
+                    },
+                },
+            )
+            .collect();
+
+        Inventory {
+            sources: Mutex::new(staged),
+        }
+    }
+
+    /// A copy of this inventory, to be refreshed without holding it up.
+    pub fn snapshot(&self) -> Inventory {
+        Inventory {
+            sources: Mutex::new(self.sources.lock().unwrap().clone()),
+        }
+    }
+
+    /// Swaps in the sources of `other`, once the config read from them is.
+    pub fn replace(&self, other: Inventory) {
+        *self.sources.lock().unwrap() = other.sources.into_inner().unwrap();
+    }
+
+    /// Takes when the sources of `snapshot` were last read and, if
+    /// `keep_data`, what they yielded. Nothing changes if the sources were
+    /// replaced since the snapshot was taken, which returns false.
+    pub fn update(&self, snapshot: Inventory, keep_data: bool) -> bool {
+        let mut sources = self.sources.lock().unwrap();
+        let refreshed = snapshot.sources.into_inner().unwrap();
+        if sources.len() != refreshed.len()
+            || sources
+                .iter()
+                .zip(&refreshed)
+                .any(|(source, refreshed)| source.config != refreshed.config)
+        {
+            return false;
+        }
+
+        for (source, refreshed) in sources.iter_mut().zip(refreshed) {
+            source.checked = refreshed.checked;
+            if keep_data {
+                source.data = refreshed.data;
+            }
+        }
+
+        true
+    }
+
+    /// Reads the sources whose data is older than their refresh interval,
+    /// or all of them if `force`. A source that fails keeps its last data
+    /// until its next refresh, it's an error only if it has none. Returns
+    /// whether some data changed.
+    pub fn refresh(&self, force: bool) -> Result<bool, Error> {
+        let mut sources = self.sources.lock().unwrap();
+        let mut changed = false;
+        for source in sources.iter_mut() {
+            let refresh = Duration::from_secs(source.config.refresh);
+            if !force && source.checked.is_some_and(|at| at.elapsed() < refresh) {
+                continue;
+            }
+
+            let fetched = source.config.fetch();
+            source.checked = Some(Instant::now());
+            match (fetched, &source.data) {
+                (Ok(data), cached) => {
+                    if cached.as_ref() != Some(&data) {
+                        info!(
+                            "Read {} nodes and {} groups from {}",
+                            data.nodes.len(),
+                            data.groups.len(),
+                            source.config.provider
+                        );
+                        changed = true;
+                    }
+                    source.data = Some(data);
+                }
+                (Err(err), Some(_)) => {
+                    warn!(
+                        "Failed to read {}, keeping its last nodes: {}",
+                        source.config.provider, err
+                    );
+                }
+                (Err(err), None) => {
+                    // Errors of the sources themselves are inventory errors already.
+                    let reason = match err {
+                        Error::Inventory(reason) => reason,
+                        err => err.to_string(),
+                    };
+                    return Err(Error::Inventory(format!(
+                        "{}: {}",
+                        source.config.provider, reason
+                    )));
+                }
+            }
+        }
+
+        Ok(changed)
+    }
+
+    /// Calls `merge` with the data of each source read so far, along with a
+    /// description of the source.
+    pub fn for_each<F>(&self, mut merge: F) -> Result<(), ConfigError>
+    where
+        F: FnMut(&InventoryData, String) -> Result<(), ConfigError>,
+    {
+        for source in self.sources.lock().unwrap().iter() {
+            if let Some(data) = &source.data {
+                merge(data, source.config.provider.to_string())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs `command`, killing it if it outlives `timeout`, and returns what it
+/// printed on stdout.
+fn run_script(command: &Path, args: &[String], timeout: Duration) -> Result<Vec<u8>, Error> {
+    let mut child = Command::new(command)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Read aside, a full pipe would keep the script from exiting.
+    let read = |mut pipe: Box<dyn Read + Send>| {
+        thread::spawn(move || {
+            let mut output = Vec::new();
+            pipe.read_to_end(&mut output).map(|_| output)
+        })
+    };
+    let stdout = read(Box::new(child.stdout.take().unwrap()));
+    let stderr = read(Box::new(child.stderr.take().unwrap()));
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if started.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(Error::Inventory(format!(
+                "timed out after {}s",
+                timeout.as_secs()
+            )));
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    let output = stdout.join().unwrap()?;
+    if !status.success() {
+        let stderr = stderr.join().unwrap().unwrap_or_default();
+        return Err(Error::Inventory(format!(
+            "exited with {}: {}",
+            status,
+            String::from_utf8_lossy(&stderr).trim()
+        )));
+    }
+
+    Ok(output)
+}
+
+/// GETs an `http://host[:port]/path` URL with HTTP/1.0, so that the body
+/// comes whole rather than chunked, and returns the body of a 200 answer.
+fn http_get(url: &str, timeout: Duration) -> Result<Vec<u8>, Error> {
+    let invalid = || Error::Inventory(format!("unsupported URL '{}'", url));
+    let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+    let (authority, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        return Err(invalid());
+    }
+    let address = if authority
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| !port.contains(']'))
+    {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+
+    let address = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+    let mut stream = TcpStream::connect_timeout(&address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\n\r\n",
+        path, authority
+    )?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let header_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| Error::Inventory("malformed HTTP response".to_string()))?;
+    let status_line = String::from_utf8_lossy(&response[..header_end])
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    if status_line.split_whitespace().nth(1) != Some("200") {
+        return Err(Error::Inventory(format!("answered '{}'", status_line)));
+    }
+
+    Ok(response.split_off(header_end + 4))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(entry: &str) -> Result<InventoryConfig, toml::de::Error> {
+        #[derive(Deserialize)]
+        struct Config {
+            inventory: Vec<InventoryConfig>,
+        }
+        let config: Config = toml::from_str(&format!("[[inventory]]\n{}", entry))?;
+        Ok(config.inventory.into_iter().next().unwrap())
+    }
+
+    #[test]
+    fn sources_build_their_provider() {
+        let mut config = parse("source = \"static\"\npath = \"par1.toml\"\nrefresh = 60").unwrap();
+        config.resolve_paths(Path::new("/etc/ovium"));
+        assert_eq!(config.refresh, 60);
+        assert_eq!(config.timeout, 30);
+        assert_eq!(
+            config.provider.to_string(),
+            "inventory file \"/etc/ovium/par1.toml\""
+        );
+
+        let config = parse("source = \"script\"\ncommand = \"inventory.sh\"").unwrap();
+        assert_eq!(
+            config.provider.to_string(),
+            "inventory script \"inventory.sh\""
+        );
+
+        let config = parse("source = \"http\"\nurl = \"http://127.0.0.1/nodes\"").unwrap();
+        assert_eq!(
+            config.provider.to_string(),
+            "inventory endpoint http://127.0.0.1/nodes"
+        );
+    }
+
+    #[test]
+    fn unknown_or_incomplete_sources_are_rejected() {
+        let err = parse("source = \"ldap\"").unwrap_err().to_string();
+        assert!(err.contains("unknown inventory source 'ldap'"), "{}", err);
+
+        let err = parse("source = \"http\"").unwrap_err().to_string();
+        assert!(err.contains("missing field `url`"), "{}", err);
+    }
+
+    #[test]
+    fn entries_compare_by_their_settings() {
+        let config = parse("source = \"script\"\ncommand = \"a.sh\"").unwrap();
+        assert_eq!(config, config.clone());
+        assert_ne!(
+            config,
+            parse("source = \"script\"\ncommand = \"a.sh\"\nargs = [\"-v\"]").unwrap()
+        );
+        assert_ne!(
+            config,
+            parse("source = \"script\"\ncommand = \"a.sh\"\nrefresh = 1").unwrap()
+        );
+    }
+
+    /// Names of the nodes `inventory` has data for.
+    fn node_names(inventory: &Inventory) -> Vec<String> {
+        let mut names = Vec::new();
+        inventory
+            .for_each(|data, _| {
+                names.extend(data.nodes.keys().cloned());
+                Ok(())
+            })
+            .unwrap();
+        names.sort();
+
+        names
+    }
+
+    #[test]
+    fn snapshot_data_is_kept_only_when_asked() {
+        let path = std::env::temp_dir().join(format!("ovium-inventory-{}", std::process::id()));
+        let write = |node: &str| {
+            fs::write(
+                &path,
+                format!("[nodes]\n{} = {{ host = \"10.0.0.1\" }}\n", node),
+            )
+            .unwrap()
+        };
+        write("web1");
+        let config = parse(&format!("source = \"static\"\npath = {:?}", path)).unwrap();
+        let inventory = Inventory::default().with_sources(&[config]);
+        assert!(inventory.refresh(true).unwrap());
+
+        write("web2");
+        let snapshot = inventory.snapshot();
+        assert!(snapshot.refresh(true).unwrap());
+        assert_eq!(node_names(&snapshot), ["web2"]);
+        // Rejected, the refresh counts but its data doesn't.
+        assert!(inventory.update(snapshot, false));
+        assert_eq!(node_names(&inventory), ["web1"]);
+        assert!(!inventory.snapshot().refresh(false).unwrap());
+
+        let snapshot = inventory.snapshot();
+        assert!(snapshot.refresh(true).unwrap());
+        assert!(inventory.update(snapshot, true));
+        assert_eq!(node_names(&inventory), ["web2"]);
+
+        // A reload replaced the sources while the snapshot was being read.
+        let snapshot = inventory.snapshot();
+        inventory.replace(Inventory::default());
+        assert!(!inventory.update(snapshot, true));
+        assert!(node_names(&inventory).is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
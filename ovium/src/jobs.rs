@@ -0,0 +1,273 @@
+use crate::acl::Peer;
+use crate::audit::now;
+use crate::history::HistoryRecorder;
+use crate::types::*;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Number of finished jobs kept for `oviumctl job`, the oldest ones are
+/// forgotten first.
+const MAX_FINISHED_JOBS: usize = 100;
+
+/// A command submitted to run in the background.
+pub struct Job {
+    pub id: u64,
+    /// Who submitted the job, the only one besides admins who may see it.
+    pub uid: u32,
+    cancelled: AtomicBool,
+    progress: Mutex<Progress>,
+    finished: Condvar,
+}
+
+struct Progress {
+    recorder: HistoryRecorder,
+    finished_at: Option<f64>,
+}
+
+impl Job {
+    pub fn record(&self, response: &Response) {
+        self.progress.lock().unwrap().recorder.record(response);
+    }
+
+    /// Marks the job as over, waking up whoever waits for it, and returns
+    /// its history entry.
+    pub fn finish(&self) -> HistoryEntry {
+        let mut progress = self.progress.lock().unwrap();
+        progress.finished_at = Some(now());
+        self.finished.notify_all();
+
+        progress.recorder.finish()
+    }
+
+    /// Asks the nodes still running to abort, and the ones not started yet
+    /// not to start.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.progress.lock().unwrap().finished_at.is_some()
+    }
+
+    /// Results of the nodes done so far.
+    pub fn results(&self) -> Vec<CmdReturn> {
+        self.progress
+            .lock()
+            .unwrap()
+            .recorder
+            .entry()
+            .results
+            .clone()
+    }
+
+    /// Blocks until the job is over, then returns its results.
+    pub fn wait(&self) -> Vec<CmdReturn> {
+        let mut progress = self.progress.lock().unwrap();
+        while progress.finished_at.is_none() {
+            progress = self.finished.wait(progress).unwrap();
+        }
+
+        progress.recorder.entry().results.clone()
+    }
+
+    pub fn info(&self) -> JobInfo {
+        let progress = self.progress.lock().unwrap();
+        let state = if self.is_cancelled() {
+            JobState::Cancelled
+        } else if progress.finished_at.is_some() {
+            JobState::Finished
+        } else {
+            JobState::Running
+        };
+
+        let entry = progress.recorder.entry();
+        JobInfo {
+            id: self.id,
+            command: entry.command.clone(),
+            nodes: entry.nodes.clone(),
+            user: entry.user.clone(),
+            state,
+            done: entry.results.len(),
+            failed: entry.failed_nodes().len(),
+            submitted_at: entry.started_at,
+            finished_at: progress.finished_at,
+        }
+    }
+}
+
+/// Jobs submitted since the server started, by id.
+#[derive(Default)]
+pub struct JobTable {
+    jobs: Mutex<BTreeMap<u64, Arc<Job>>>,
+}
+
+impl JobTable {
+    /// Registers the job of request `id`, running `req` on `nodes` for `peer`.
+    pub fn submit(&self, id: u64, peer: &Peer, req: &CmdRequest, nodes: &[&String]) -> Arc<Job> {
+        let job = Arc::new(Job {
+            id,
+            uid: peer.uid,
+            cancelled: AtomicBool::new(false),
+            progress: Mutex::new(Progress {
+                recorder: HistoryRecorder::new(id, peer, req, nodes, true),
+                finished_at: None,
+            }),
+            finished: Condvar::new(),
+        });
+
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.insert(id, job.clone());
+        let finished: Vec<u64> = jobs
+            .values()
+            .filter(|job| job.is_finished())
+            .map(|job| job.id)
+            .collect();
+        for id in finished
+            .iter()
+            .take(finished.len().saturating_sub(MAX_FINISHED_JOBS))
+        {
+            jobs.remove(id);
+        }
+
+        job
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<Job>> {
+        self.jobs.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Every job, from the oldest to the newest.
+    pub fn list(&self) -> Vec<Arc<Job>> {
+        self.jobs.lock().unwrap().values().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    fn peer() -> Peer {
+        Peer {
+            uid: 1000,
+            gid: 1000,
+            pid: 1,
+            user: Some("deploy".to_string()),
+            groups: Vec::new(),
+        }
+    }
+
+    fn submit(jobs: &JobTable, id: u64) -> Arc<Job> {
+        let req = CmdRequest {
+            nodes: vec!["web".to_string()],
+            command: "uptime".to_string(),
+            stream: false,
+            timeouts: Timeouts::default(),
+            parallelism: None,
+            batch: None,
+        };
+        let nodes = ["web1".to_string(), "web2".to_string()];
+        jobs.submit(id, &peer(), &req, &nodes.iter().collect::<Vec<_>>())
+    }
+
+    fn status(node_name: &str, exit_status: i32) -> Response {
+        Response::CmdStatus(CmdReturn {
+            node_name: node_name.to_string(),
+            data: SshReturn::SshSuccess(SshSuccess {
+                stdout: None,
+                stderr: None,
+                exit_status,
+            }),
+            duration: Duration::ZERO,
+        })
+    }
+
+    #[test]
+    fn submitted_job_reports_its_progress() {
+        let jobs = JobTable::default();
+        let job = submit(&jobs, 1);
+        assert_eq!(job.uid, 1000);
+        assert!(Arc::ptr_eq(&jobs.get(1).unwrap(), &job));
+        assert!(jobs.get(2).is_none());
+
+        job.record(&status("web1", 2));
+        let info = job.info();
+        assert_eq!(info.state, JobState::Running);
+        assert_eq!(info.nodes, ["web1", "web2"]);
+        assert_eq!((info.done, info.failed), (1, 1));
+        assert!(info.finished_at.is_none());
+        assert_eq!(job.results().len(), 1);
+    }
+
+    #[test]
+    fn finished_job_hands_over_its_history_entry() {
+        let jobs = JobTable::default();
+        let job = submit(&jobs, 1);
+        job.record(&status("web1", 0));
+        job.record(&status("web2", 0));
+
+        let entry = job.finish();
+        assert!(entry.job);
+        assert_eq!(entry.id, 1);
+        assert_eq!(entry.results.len(), 2);
+        let info = job.info();
+        assert_eq!(info.state, JobState::Finished);
+        assert!(info.finished_at.is_some());
+    }
+
+    #[test]
+    fn wait_blocks_until_the_job_is_over() {
+        let jobs = JobTable::default();
+        let job = submit(&jobs, 1);
+        let waiter = {
+            let job = job.clone();
+            thread::spawn(move || job.wait())
+        };
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+        job.record(&status("web1", 0));
+        job.finish();
+        assert_eq!(waiter.join().unwrap().len(), 1);
+        // Once over, waiting returns right away.
+        assert_eq!(job.wait().len(), 1);
+    }
+
+    #[test]
+    fn cancelled_job_stays_cancelled() {
+        let jobs = JobTable::default();
+        let job = submit(&jobs, 1);
+        assert!(!job.is_cancelled());
+
+        job.cancel();
+        assert!(job.is_cancelled());
+        assert_eq!(job.info().state, JobState::Cancelled);
+        job.finish();
+        assert_eq!(job.info().state, JobState::Cancelled);
+    }
+
+    #[test]
+    fn oldest_finished_jobs_are_forgotten() {
+        let jobs = JobTable::default();
+        let running = submit(&jobs, 1);
+        let count = MAX_FINISHED_JOBS as u64 + 3;
+        for id in 2..=count + 1 {
+            submit(&jobs, id).finish();
+        }
+        // Finished jobs are only pruned when another one is submitted.
+        submit(&jobs, count + 2);
+
+        let ids: Vec<u64> = jobs.list().iter().map(|job| job.id).collect();
+        assert_eq!(ids.len(), MAX_FINISHED_JOBS + 2);
+        assert_eq!(ids[..3], [1, 5, 6]);
+        assert_eq!(*ids.last().unwrap(), count + 2);
+        assert!(!running.is_finished());
+        assert!(jobs.get(2).is_none());
+    }
+}
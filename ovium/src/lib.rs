@@ -1,5 +1,14 @@
+pub mod acl;
+pub mod audit;
 pub mod client;
 pub mod error;
 pub mod handlers;
+pub mod history;
+pub mod inventory;
+pub mod jobs;
+pub mod logging;
+pub mod output;
+pub mod pool;
+pub mod selector;
 pub mod server;
 pub mod types;
@@ -0,0 +1,261 @@
+use crate::server::config_files;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Mutex;
+
+/// `[log]` section of the config.
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct LogConfig {
+    #[serde(default)]
+    pub level: LogLevel,
+    /// Where log lines go, only read at startup.
+    #[serde(default)]
+    pub target: LogTarget,
+    /// Log file of the `file` target, relative to the config directory.
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+/// `Terminal` writes to stderr, coloured when it's a terminal. `Journald`
+/// also writes to stderr, with the priority prefixes journald understands.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTarget {
+    #[default]
+    Terminal,
+    File,
+    Syslog,
+    Journald,
+}
+
+const LEVELS: [LevelFilter; 6] = [
+    LevelFilter::Off,
+    LevelFilter::Error,
+    LevelFilter::Warn,
+    LevelFilter::Info,
+    LevelFilter::Debug,
+    LevelFilter::Trace,
+];
+
+/// Levels added with `-v` or removed with `-q`, kept to apply them on top of
+/// a reloaded config level.
+static VERBOSITY: AtomicI32 = AtomicI32::new(0);
+
+thread_local! {
+    static CONTEXT: RefCell<LogContext> = RefCell::new(LogContext::default());
+}
+
+/// Fields prepended to the log lines of the current thread.
+#[derive(Debug, Default, Clone)]
+pub struct LogContext {
+    pub request_id: Option<u64>,
+    pub node: Option<String>,
+}
+
+impl LogContext {
+    /// This context, about `node`.
+    pub fn with_node(&self, node: &str) -> LogContext {
+        LogContext {
+            node: Some(node.to_string()),
+            ..self.clone()
+        }
+    }
+}
+
+impl fmt::Display for LogContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(request_id) = self.request_id {
+            write!(f, "request={} ", request_id)?;
+        }
+        if let Some(node) = &self.node {
+            write!(f, "node={} ", node)?;
+        }
+        Ok(())
+    }
+}
+
+/// The context of the current thread, to hand over to the threads it spawns.
+pub fn context() -> LogContext {
+    CONTEXT.with(|context| context.borrow().clone())
+}
+
+pub fn set_context(context: LogContext) {
+    CONTEXT.with(|current| *current.borrow_mut() = context);
+}
+
+impl LogConfig {
+    /// Reads only the `[log]` section of the config, so that logging can be
+    /// set up before the rest of the config is validated. Falls back to the
+    /// defaults if it can't.
+    pub fn load(config_dir: &Path) -> LogConfig {
+        #[derive(Deserialize)]
+        struct LogSection {
+            log: Option<LogConfig>,
+        }
+
+        let mut log_config = config_files(config_dir)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|file| fs::read_to_string(file).ok())
+            .filter_map(|config| toml::from_str::<LogSection>(&config).ok())
+            .find_map(|section| section.log)
+            .unwrap_or_default();
+        log_config.resolve_paths(config_dir);
+
+        log_config
+    }
+
+    pub fn resolve_paths(&mut self, config_dir: &Path) {
+        if let Some(file) = &self.file {
+            self.file = Some(config_dir.join(file));
+        }
+    }
+}
+
+/// Installs the logger of `program`, `verbosity` levels above (or below, if
+/// negative) the configured one.
+pub fn init(log_config: &LogConfig, verbosity: i32, program: &str) -> io::Result<()> {
+    let backend = match log_config.target {
+        LogTarget::Terminal => Backend::Terminal {
+            colors: io::stderr().is_terminal(),
+        },
+        LogTarget::Journald => Backend::Journald,
+        LogTarget::File => {
+            let path = log_config.file.as_ref().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the file log target needs a file",
+                )
+            })?;
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            Backend::File(Mutex::new(file))
+        }
+        LogTarget::Syslog => {
+            // openlog keeps the pointer, the identity must outlive the logger.
+            let ident: &'static CString = Box::leak(Box::new(CString::new(program)?));
+            unsafe { libc::openlog(ident.as_ptr(), libc::LOG_PID, libc::LOG_DAEMON) };
+            Backend::Syslog
+        }
+    };
+
+    VERBOSITY.store(verbosity, Ordering::SeqCst);
+    log::set_boxed_logger(Box::new(Logger { backend }))
+        .map_err(|err| io::Error::new(io::ErrorKind::AlreadyExists, err.to_string()))?;
+    set_level(log_config.level);
+
+    Ok(())
+}
+
+/// Applies `level`, e.g. after a config reload, along with the verbosity
+/// asked for on the command line.
+pub fn set_level(level: LogLevel) {
+    let index = level as i32 + VERBOSITY.load(Ordering::SeqCst);
+    log::set_max_level(LEVELS[index.clamp(0, LEVELS.len() as i32 - 1) as usize]);
+}
+
+enum Backend {
+    Terminal { colors: bool },
+    File(Mutex<File>),
+    Syslog,
+    Journald,
+}
+
+struct Logger {
+    backend: Backend,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let level = record.level();
+        let context = context();
+        match &self.backend {
+            Backend::Terminal { colors } => {
+                let (start, end) = match (colors, level_color(level)) {
+                    (true, Some(color)) => (color, "\x1b[0m"),
+                    _ => ("", ""),
+                };
+                eprintln!(
+                    "{} [{}{:>5}{}] {}{}",
+                    chrono::Local::now().format("%H:%M:%S"),
+                    start,
+                    level,
+                    end,
+                    context,
+                    record.args()
+                );
+            }
+            Backend::File(file) => {
+                let mut file = file.lock().unwrap();
+                // Nowhere left to report a failure to write the log.
+                let _ = writeln!(
+                    file,
+                    "{} [{:>5}] {}{}",
+                    chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+                    level,
+                    context,
+                    record.args()
+                );
+            }
+            Backend::Syslog => {
+                let line = format!("{}{}", context, record.args()).replace('\0', "");
+                if let Ok(line) = CString::new(line) {
+                    unsafe { libc::syslog(priority(level), c"%s".as_ptr(), line.as_ptr()) };
+                }
+            }
+            Backend::Journald => {
+                eprintln!("<{}>{}{}", priority(level), context, record.args());
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Backend::File(file) = &self.backend {
+            let _ = file.lock().unwrap().flush();
+        }
+    }
+}
+
+fn level_color(level: Level) -> Option<&'static str> {
+    match level {
+        Level::Error => Some("\x1b[31m"),
+        Level::Warn => Some("\x1b[33m"),
+        Level::Info => Some("\x1b[34m"),
+        Level::Debug | Level::Trace => None,
+    }
+}
+
+/// Syslog priority of `level`, also used by journald.
+fn priority(level: Level) -> libc::c_int {
+    match level {
+        Level::Error => libc::LOG_ERR,
+        Level::Warn => libc::LOG_WARNING,
+        Level::Info => libc::LOG_INFO,
+        Level::Debug | Level::Trace => libc::LOG_DEBUG,
+    }
+}
@@ -0,0 +1,371 @@
+use crate::error::Error;
+use crate::types::*;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// How `oviumctl` prints the responses it gets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// Human readable, coloured when stdout is a terminal.
+    Text,
+    /// A single array of node records, printed once every node is done.
+    Json,
+    /// One node record per line, printed as soon as the node is done.
+    Jsonl,
+    /// Same as `Json`, as a YAML document.
+    Yaml,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" => Ok(OutputFormat::Jsonl),
+            "yaml" => Ok(OutputFormat::Yaml),
+            _ => Err(format!(
+                "'{}' is not one of text, json, jsonl or yaml",
+                format
+            )),
+        }
+    }
+}
+
+/// The outcome of a request on one node, as printed by the structured
+/// output formats.
+#[derive(Serialize, Debug)]
+pub struct NodeRecord {
+    pub node: String,
+    /// One of `success`, `failed` for a command that exited with a non-zero
+    /// code, `failure` when the node couldn't run it, `timeout`, `skipped` or
+    /// `aborted`.
+    pub status: &'static str,
+    pub exit_code: Option<i32>,
+    pub stdout: Option<String>,
+    pub stderr: Option<String>,
+    /// Seconds spent on the node, unknown for file transfers.
+    pub duration: Option<f64>,
+    pub error: Option<String>,
+}
+
+impl NodeRecord {
+    fn new(node: String, status: &'static str) -> NodeRecord {
+        NodeRecord {
+            node,
+            status,
+            exit_code: None,
+            stdout: None,
+            stderr: None,
+            duration: None,
+            error: None,
+        }
+    }
+}
+
+impl From<CmdReturn> for NodeRecord {
+    fn from(cmd_return: CmdReturn) -> Self {
+        let duration = Some(cmd_return.duration.as_secs_f64());
+        let mut record = match cmd_return.data {
+            SshReturn::SshSuccess(success) => NodeRecord {
+                exit_code: Some(success.exit_status),
+                stdout: success.stdout,
+                stderr: success.stderr,
+                ..NodeRecord::new(
+                    cmd_return.node_name,
+                    if success.exit_status == 0 {
+                        "success"
+                    } else {
+                        "failed"
+                    },
+                )
+            },
+            SshReturn::SshFailure(failure) => NodeRecord {
+                error: Some(failure),
+                ..NodeRecord::new(cmd_return.node_name, "failure")
+            },
+            SshReturn::SshTimeout(stage, seconds) => NodeRecord {
+                error: Some(format!("{} timed out after {}s", stage, seconds)),
+                ..NodeRecord::new(cmd_return.node_name, "timeout")
+            },
+            SshReturn::SshSkipped(failure_ratio) => NodeRecord {
+                error: Some(format!(
+                    "rollout stopped after a batch with {:.0}% failures",
+                    failure_ratio * 100.0
+                )),
+                ..NodeRecord::new(cmd_return.node_name, "skipped")
+            },
+            SshReturn::SshAborted => NodeRecord {
+                error: Some("cancelled before the command finished".to_string()),
+                ..NodeRecord::new(cmd_return.node_name, "aborted")
+            },
+        };
+        record.duration = duration;
+
+        record
+    }
+}
+
+impl From<TransferReturn> for NodeRecord {
+    fn from(transfer_return: TransferReturn) -> Self {
+        match transfer_return.data {
+            TransferResult::Uploaded(_) | TransferResult::Downloaded { .. } => {
+                NodeRecord::new(transfer_return.node_name, "success")
+            }
+            TransferResult::SshFailure(failure) => NodeRecord {
+                error: Some(failure),
+                ..NodeRecord::new(transfer_return.node_name, "failure")
+            },
+            TransferResult::SshTimeout(stage, seconds) => NodeRecord {
+                error: Some(format!("{} timed out after {}s", stage, seconds)),
+                ..NodeRecord::new(transfer_return.node_name, "timeout")
+            },
+        }
+    }
+}
+
+/// Prints the responses of a request in the chosen `OutputFormat`.
+pub struct Printer {
+    format: OutputFormat,
+    records: Vec<NodeRecord>,
+    /// Output streamed by each node, until its final status comes in.
+    streamed: HashMap<String, (String, String)>,
+}
+
+impl Printer {
+    pub fn new(format: OutputFormat) -> Printer {
+        Printer {
+            format,
+            records: Vec::new(),
+            streamed: HashMap::new(),
+        }
+    }
+
+    pub fn handle(&mut self, response: Response) -> Result<(), Error> {
+        if self.format == OutputFormat::Text {
+            return ClientHandler::new(response).handle();
+        }
+
+        match response {
+            Response::Cmd(cmd_returns) => {
+                for cmd_return in cmd_returns {
+                    self.record(cmd_return.into())?;
+                }
+            }
+            Response::CmdOutput(output) => {
+                let (stdout, stderr) = self.streamed.entry(output.node_name).or_default();
+                match output.kind {
+                    OutputKind::Stdout => stdout.push_str(&output.data),
+                    OutputKind::Stderr => stderr.push_str(&output.data),
+                }
+            }
+            Response::CmdStatus(cmd_return) => {
+                let streamed = self.streamed.remove(&cmd_return.node_name);
+                let mut record = NodeRecord::from(cmd_return);
+                if let Some((stdout, stderr)) = streamed {
+                    record.stdout = record.stdout.or(Some(stdout));
+                    record.stderr = record.stderr.or(Some(stderr));
+                }
+                self.record(record)?;
+            }
+            Response::Transfer(transfer_return) => self.record(transfer_return.into())?,
+            Response::Status(status) => self.print(&status)?,
+            Response::Reload(report) => self.print(&report)?,
+            Response::Job(job) => self.print(&job)?,
+            Response::Jobs(jobs) => self.print_list(&jobs)?,
+            Response::History(entries) => self.print_list(&entries)?,
+            Response::HistoryEntry(entry) => self.print(&entry)?,
+            Response::Error(err) => eprintln!("{}", err),
+            Response::Hello(_) => (),
+        }
+
+        Ok(())
+    }
+
+    /// Prints the records held back until every node was done.
+    pub fn finish(self) -> Result<(), Error> {
+        match self.format {
+            OutputFormat::Json | OutputFormat::Yaml if !self.records.is_empty() => {
+                self.print(&self.records)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn record(&mut self, record: NodeRecord) -> Result<(), Error> {
+        match self.format {
+            OutputFormat::Jsonl => self.print(&record),
+            _ => {
+                self.records.push(record);
+                Ok(())
+            }
+        }
+    }
+
+    /// Prints `values` as a whole, or one per line as `Jsonl`.
+    fn print_list<T: Serialize>(&self, values: &[T]) -> Result<(), Error> {
+        match self.format {
+            OutputFormat::Jsonl => values.iter().try_for_each(|value| self.print(value)),
+            _ => self.print(&values),
+        }
+    }
+
+    fn print<T: Serialize>(&self, value: &T) -> Result<(), Error> {
+        print!("{}", render(self.format, value)?);
+
+        Ok(())
+    }
+}
+
+/// `value` as printed in `format`, nothing for `Text` which has its own
+/// handlers.
+fn render<T: Serialize>(format: OutputFormat, value: &T) -> Result<String, Error> {
+    Ok(match format {
+        OutputFormat::Text => String::new(),
+        OutputFormat::Json => serde_json::to_string_pretty(value)? + "\n",
+        OutputFormat::Jsonl => serde_json::to_string(value)? + "\n",
+        OutputFormat::Yaml => serde_yaml::to_string(value)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn cmd_return(node_name: &str, data: SshReturn) -> CmdReturn {
+        CmdReturn {
+            node_name: node_name.to_string(),
+            data,
+            duration: Duration::from_millis(1500),
+        }
+    }
+
+    fn exited(exit_status: i32, stdout: &str) -> SshReturn {
+        SshReturn::SshSuccess(SshSuccess {
+            stdout: Some(stdout.to_string()),
+            stderr: Some(String::new()),
+            exit_status,
+        })
+    }
+
+    /// A success, a non-zero exit, a timeout and a skipped node.
+    fn results() -> Vec<CmdReturn> {
+        vec![
+            cmd_return("web1", exited(0, "up 3 days\n")),
+            cmd_return("web2", exited(3, "")),
+            cmd_return("web3", SshReturn::SshTimeout(TimeoutStage::Exec, 5)),
+            cmd_return("web4", SshReturn::SshSkipped(0.5)),
+        ]
+    }
+
+    fn expected() -> serde_json::Value {
+        json!([
+            {
+                "node": "web1",
+                "status": "success",
+                "exit_code": 0,
+                "stdout": "up 3 days\n",
+                "stderr": "",
+                "duration": 1.5,
+                "error": null,
+            },
+            {
+                "node": "web2",
+                "status": "failed",
+                "exit_code": 3,
+                "stdout": "",
+                "stderr": "",
+                "duration": 1.5,
+                "error": null,
+            },
+            {
+                "node": "web3",
+                "status": "timeout",
+                "exit_code": null,
+                "stdout": null,
+                "stderr": null,
+                "duration": 1.5,
+                "error": "exec timed out after 5s",
+            },
+            {
+                "node": "web4",
+                "status": "skipped",
+                "exit_code": null,
+                "stdout": null,
+                "stderr": null,
+                "duration": 1.5,
+                "error": "rollout stopped after a batch with 50% failures",
+            },
+        ])
+    }
+
+    /// The records `format` holds back once `responses` are handled.
+    fn records(format: OutputFormat, responses: Vec<Response>) -> Vec<NodeRecord> {
+        let mut printer = Printer::new(format);
+        for response in responses {
+            printer.handle(response).unwrap();
+        }
+
+        printer.records
+    }
+
+    #[test]
+    fn json_renders_every_node_in_one_array() {
+        let records = records(OutputFormat::Json, vec![Response::Cmd(results())]);
+        let rendered = render(OutputFormat::Json, &records).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&rendered).unwrap();
+        assert_eq!(value, expected());
+    }
+
+    #[test]
+    fn jsonl_renders_one_node_per_line() {
+        for (cmd_return, expected) in results().into_iter().zip(expected().as_array().unwrap()) {
+            let rendered = render(OutputFormat::Jsonl, &NodeRecord::from(cmd_return)).unwrap();
+            assert_eq!(rendered.matches('\n').count(), 1, "{}", rendered);
+            let value: serde_json::Value = serde_json::from_str(&rendered).unwrap();
+            assert_eq!(&value, expected);
+        }
+    }
+
+    #[test]
+    fn yaml_renders_the_same_records() {
+        let records = records(OutputFormat::Yaml, vec![Response::Cmd(results())]);
+        let rendered = render(OutputFormat::Yaml, &records).unwrap();
+        assert!(rendered.contains("status: failed"), "{}", rendered);
+        let value: serde_json::Value = serde_yaml::from_str(&rendered).unwrap();
+        assert_eq!(value, expected());
+    }
+
+    #[test]
+    fn streamed_output_goes_into_the_node_record() {
+        let output = |kind, data: &str| {
+            Response::CmdOutput(CmdOutput {
+                node_name: "web1".to_string(),
+                kind,
+                data: data.to_string(),
+            })
+        };
+        let mut status = cmd_return("web1", exited(0, ""));
+        if let SshReturn::SshSuccess(success) = &mut status.data {
+            success.stdout = None;
+            success.stderr = None;
+        }
+
+        let records = records(
+            OutputFormat::Json,
+            vec![
+                output(OutputKind::Stdout, "up "),
+                output(OutputKind::Stderr, "warning\n"),
+                output(OutputKind::Stdout, "3 days\n"),
+                Response::CmdStatus(status),
+            ],
+        );
+        let mut expected = expected()[0].clone();
+        expected["stderr"] = json!("warning\n");
+        assert_eq!(serde_json::to_value(&records).unwrap(), json!([expected]));
+    }
+}
@@ -0,0 +1,402 @@
+use crate::server::{HostKeyConfig, SshSettings};
+use crate::types::{Auth, Node, PoolStatus};
+use log::{debug, info};
+use serde::Deserialize;
+use ssh2::Session;
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PoolConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Seconds an unused session is kept open.
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// Seconds between two keepalives sent on idle sessions.
+    #[serde(default = "default_keepalive_interval")]
+    pub keepalive_interval: u64,
+    #[serde(default = "default_max_idle_per_node")]
+    pub max_idle_per_node: usize,
+}
+
+/// Authenticated sessions left open between requests, keyed on the node
+/// name. A session is only handed back for the exact `Node` entry and SSH
+/// settings it was opened with, so a node edited in the config, or a change
+/// of the default auth or host key policy, gets a fresh one.
+pub struct SessionPool {
+    config: RwLock<PoolConfig>,
+    nodes: Mutex<HashMap<String, NodeSessions>>,
+    /// Whether an idle session still answers, `is_healthy` but in tests.
+    healthy: fn(&Session) -> bool,
+}
+
+struct IdleSession {
+    opened_with: OpenedWith,
+    session: Session,
+    idle_since: Instant,
+}
+
+/// What a session was opened and verified with, everything but the timeouts
+/// of its `SshSettings`.
+#[derive(PartialEq)]
+struct OpenedWith {
+    node: Node,
+    auth: Auth,
+    host_keys: HostKeyConfig,
+    jump_hosts: Vec<(String, Node, Auth)>,
+}
+
+impl OpenedWith {
+    fn new(node: &Node, settings: &SshSettings) -> OpenedWith {
+        OpenedWith {
+            node: node.clone(),
+            auth: settings.auth.clone(),
+            host_keys: settings.host_keys.clone(),
+            jump_hosts: settings
+                .jump_hosts
+                .iter()
+                .map(|&(name, jump_host, auth)| (name.to_string(), jump_host.clone(), auth.clone()))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct NodeSessions {
+    idle: Vec<IdleSession>,
+    opened: u64,
+    reused: u64,
+    discarded: u64,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            enabled: default_enabled(),
+            idle_timeout: default_idle_timeout(),
+            keepalive_interval: default_keepalive_interval(),
+            max_idle_per_node: default_max_idle_per_node(),
+        }
+    }
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_idle_timeout() -> u64 {
+    300
+}
+
+fn default_keepalive_interval() -> u64 {
+    30
+}
+
+fn default_max_idle_per_node() -> usize {
+    4
+}
+
+impl SessionPool {
+    pub fn new(config: PoolConfig) -> SessionPool {
+        SessionPool {
+            config: RwLock::new(config),
+            nodes: Mutex::new(HashMap::new()),
+            healthy: is_healthy,
+        }
+    }
+
+    /// Applies the settings of a reloaded configuration, idle sessions are
+    /// kept.
+    pub fn set_config(&self, config: PoolConfig) {
+        *self.config.write().unwrap() = config;
+    }
+
+    pub fn keepalive_interval(&self) -> Duration {
+        Duration::from_secs(self.config().keepalive_interval.max(1))
+    }
+
+    /// Takes an idle session opened for `node` with `settings`, skipping the
+    /// ones that expired or don't answer anymore.
+    pub fn take(&self, node_name: &str, node: &Node, settings: &SshSettings) -> Option<Session> {
+        if !self.config().enabled {
+            return None;
+        }
+
+        let candidates = match self.nodes.lock().unwrap().get_mut(node_name) {
+            Some(sessions) => std::mem::take(&mut sessions.idle),
+            None => return None,
+        };
+
+        let opened_with = OpenedWith::new(node, settings);
+        let mut found = None;
+        let mut discarded = 0;
+        let mut kept = Vec::new();
+        for idle in candidates {
+            if found.is_some() {
+                kept.push(idle);
+            } else if idle.opened_with == opened_with
+                && !self.is_expired(&idle)
+                && (self.healthy)(&idle.session)
+            {
+                found = Some(idle.session);
+            } else {
+                discarded += 1;
+            }
+        }
+
+        let mut nodes = self.nodes.lock().unwrap();
+        let sessions = nodes.entry(node_name.to_string()).or_default();
+        sessions.idle.extend(kept);
+        sessions.discarded += discarded;
+        if found.is_some() {
+            sessions.reused += 1;
+            debug!("Reusing pooled session for node {}", node_name);
+        }
+
+        found
+    }
+
+    /// Records a session freshly opened for `node_name`.
+    pub fn opened(&self, node_name: &str) {
+        let mut nodes = self.nodes.lock().unwrap();
+        nodes.entry(node_name.to_string()).or_default().opened += 1;
+    }
+
+    /// Hands a session opened for `node` with `settings` back once a command
+    /// is done with it.
+    pub fn put(&self, node_name: &str, node: &Node, settings: &SshSettings, session: Session) {
+        let config = self.config();
+        if !config.enabled {
+            return;
+        }
+
+        session.set_keepalive(true, config.keepalive_interval as u32);
+        let mut nodes = self.nodes.lock().unwrap();
+        let sessions = nodes.entry(node_name.to_string()).or_default();
+        if sessions.idle.len() >= config.max_idle_per_node {
+            sessions.discarded += 1;
+            return;
+        }
+        sessions.idle.push(IdleSession {
+            opened_with: OpenedWith::new(node, settings),
+            session,
+            idle_since: Instant::now(),
+        });
+    }
+
+    /// Closes the expired sessions and keeps the others alive. The sessions
+    /// are checked out of the pool, so that a node slow to answer its
+    /// keepalive doesn't hold up the other nodes' checkouts and checkins.
+    pub fn maintain(&self) {
+        let checked_out: Vec<(String, Vec<IdleSession>)> = self
+            .nodes
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .map(|(node_name, sessions)| (node_name.clone(), std::mem::take(&mut sessions.idle)))
+            .collect();
+
+        for (node_name, idle) in checked_out {
+            let before = idle.len();
+            let alive: Vec<IdleSession> = idle
+                .into_iter()
+                .filter(|idle| !self.is_expired(idle) && (self.healthy)(&idle.session))
+                .collect();
+            let mut dropped = before - alive.len();
+
+            let max_idle = self.config().max_idle_per_node;
+            let mut nodes = self.nodes.lock().unwrap();
+            let sessions = nodes.entry(node_name.clone()).or_default();
+            // Sessions handed back meanwhile count toward the limit too.
+            for idle in alive {
+                if sessions.idle.len() < max_idle {
+                    sessions.idle.push(idle);
+                } else {
+                    dropped += 1;
+                }
+            }
+            if dropped > 0 {
+                info!("Closed {} idle session(s) to node {}", dropped, node_name);
+                sessions.discarded += dropped as u64;
+            }
+        }
+    }
+
+    pub fn status(&self) -> Vec<PoolStatus> {
+        let nodes = self.nodes.lock().unwrap();
+        let mut status: Vec<PoolStatus> = nodes
+            .iter()
+            .map(|(node_name, sessions)| PoolStatus {
+                node_name: node_name.clone(),
+                idle_sessions: sessions.idle.len(),
+                opened: sessions.opened,
+                reused: sessions.reused,
+                discarded: sessions.discarded,
+            })
+            .collect();
+        status.sort_by(|a, b| a.node_name.cmp(&b.node_name));
+
+        status
+    }
+
+    fn is_expired(&self, idle: &IdleSession) -> bool {
+        idle.idle_since.elapsed() >= Duration::from_secs(self.config().idle_timeout)
+    }
+
+    fn config(&self) -> PoolConfig {
+        self.config.read().unwrap().clone()
+    }
+}
+
+/// A keepalive can only be written on a live connection.
+fn is_healthy(session: &Session) -> bool {
+    session.authenticated() && session.keepalive_send().is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ServerConfig;
+    use crate::types::Timeouts;
+
+    fn node(host: &str) -> Node {
+        toml::from_str(&format!("host = \"{}\"", host)).unwrap()
+    }
+
+    /// A pool whose sessions are all healthy, unless `healthy` says
+    /// otherwise, since they're never actually connected.
+    fn pool(config: PoolConfig, healthy: fn(&Session) -> bool) -> SessionPool {
+        let mut pool = SessionPool::new(config);
+        pool.healthy = healthy;
+        pool
+    }
+
+    fn status(pool: &SessionPool) -> (usize, u64, u64) {
+        let status = pool.status();
+        (
+            status[0].idle_sessions,
+            status[0].reused,
+            status[0].discarded,
+        )
+    }
+
+    #[test]
+    fn session_is_reused_for_the_same_node_and_settings() {
+        let pool = pool(PoolConfig::default(), |_| true);
+        let config = ServerConfig::default();
+        let web1 = node("10.0.0.1");
+        let settings = config.ssh_settings(&web1, Timeouts::default());
+
+        pool.put("web1", &web1, &settings, Session::new().unwrap());
+        assert_eq!(status(&pool), (1, 0, 0));
+        // Other timeouts don't make another session.
+        let settings = config.ssh_settings(&web1, Timeouts::BUILTIN);
+        assert!(pool.take("web2", &web1, &settings).is_none());
+        assert!(pool.take("web1", &web1, &settings).is_some());
+        assert_eq!(status(&pool), (0, 1, 0));
+        assert!(pool.take("web1", &web1, &settings).is_none());
+    }
+
+    #[test]
+    fn session_opened_with_other_settings_is_discarded() {
+        let pool = pool(PoolConfig::default(), |_| true);
+        let config = ServerConfig::default();
+        let web1 = node("10.0.0.1");
+        pool.put(
+            "web1",
+            &web1,
+            &config.ssh_settings(&web1, Timeouts::default()),
+            Session::new().unwrap(),
+        );
+
+        let moved = node("10.0.0.2");
+        let settings = config.ssh_settings(&moved, Timeouts::default());
+        assert!(pool.take("web1", &moved, &settings).is_none());
+        assert_eq!(status(&pool), (0, 0, 1));
+
+        let mut config = ServerConfig::default();
+        pool.put(
+            "web1",
+            &web1,
+            &config.ssh_settings(&web1, Timeouts::default()),
+            Session::new().unwrap(),
+        );
+        config.defaults.auth = Some(Auth::Password {
+            password_file: "/etc/ovium/password".into(),
+        });
+        let settings = config.ssh_settings(&web1, Timeouts::default());
+        assert!(pool.take("web1", &web1, &settings).is_none());
+        assert_eq!(status(&pool), (0, 0, 2));
+    }
+
+    #[test]
+    fn expired_and_dead_sessions_are_closed() {
+        let config = ServerConfig::default();
+        let web1 = node("10.0.0.1");
+        let settings = config.ssh_settings(&web1, Timeouts::default());
+
+        let expiring = pool(
+            PoolConfig {
+                idle_timeout: 0,
+                ..PoolConfig::default()
+            },
+            |_| true,
+        );
+        expiring.put("web1", &web1, &settings, Session::new().unwrap());
+        assert!(expiring.take("web1", &web1, &settings).is_none());
+        expiring.put("web1", &web1, &settings, Session::new().unwrap());
+        expiring.maintain();
+        assert_eq!(status(&expiring), (0, 0, 2));
+
+        let dead = pool(PoolConfig::default(), |_| false);
+        dead.put("web1", &web1, &settings, Session::new().unwrap());
+        dead.maintain();
+        assert_eq!(status(&dead), (0, 0, 1));
+
+        let alive = pool(PoolConfig::default(), |_| true);
+        alive.put("web1", &web1, &settings, Session::new().unwrap());
+        alive.maintain();
+        assert_eq!(status(&alive), (1, 0, 0));
+    }
+
+    #[test]
+    fn idle_sessions_are_capped_per_node() {
+        let pool = pool(
+            PoolConfig {
+                max_idle_per_node: 1,
+                ..PoolConfig::default()
+            },
+            |_| true,
+        );
+        let config = ServerConfig::default();
+        let web1 = node("10.0.0.1");
+        let settings = config.ssh_settings(&web1, Timeouts::default());
+        pool.put("web1", &web1, &settings, Session::new().unwrap());
+        pool.put("web1", &web1, &settings, Session::new().unwrap());
+        assert_eq!(status(&pool), (1, 0, 1));
+    }
+
+    #[test]
+    fn slow_keepalive_doesnt_block_the_pool() {
+        let pool = pool(PoolConfig::default(), |_| {
+            std::thread::sleep(Duration::from_millis(500));
+            true
+        });
+        let config = ServerConfig::default();
+        let web1 = node("10.0.0.1");
+        let settings = config.ssh_settings(&web1, Timeouts::default());
+        pool.put("web1", &web1, &settings, Session::new().unwrap());
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| pool.maintain());
+            std::thread::sleep(Duration::from_millis(100));
+            let started = Instant::now();
+            pool.put("web2", &web1, &settings, Session::new().unwrap());
+            pool.status();
+            assert!(started.elapsed() < Duration::from_millis(200));
+        });
+        assert_eq!(pool.status()[0].idle_sessions, 1);
+    }
+}
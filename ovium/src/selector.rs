@@ -0,0 +1,356 @@
+use crate::acl::glob_match;
+use crate::error::SelectorError;
+use crate::server::ServerConfig;
+use crate::types::Node;
+use regex::Regex;
+use std::collections::BTreeSet;
+
+/// A parsed node selector, such as `web-*&!role=db,/db[0-9]+/`.
+///
+/// Terms are node or group names, globs on node names where `*` matches any
+/// run of characters and `?` a single one, regexes on node names between
+/// slashes, or `key=value` labels whose value may be a glob. `!` excludes
+/// what the term after it selects, `&` intersects and `,` unites, from the
+/// tightest to the loosest. Parentheses group.
+#[derive(Debug)]
+pub enum Selector {
+    Name(String),
+    Glob(String),
+    /// The regex as written, and compiled to match whole node names.
+    Regex(String, Regex),
+    Label {
+        key: String,
+        value: String,
+    },
+    Union(Vec<Selector>),
+    Intersection(Vec<Selector>),
+    Not(Box<Selector>),
+}
+
+/// Characters ending a term outside of a regex.
+const OPERATORS: &[char] = &[',', '&', '!', '(', ')'];
+
+impl Selector {
+    pub fn parse(input: &str) -> Result<Selector, SelectorError> {
+        let mut parser = Parser {
+            chars: input.chars().collect(),
+            pos: 0,
+        };
+        let selector = parser.union()?;
+        parser.skip_spaces();
+        match parser.peek() {
+            None => Ok(selector),
+            Some(c) => Err(parser.error(&format!("unexpected '{}'", c))),
+        }
+    }
+
+    /// Nodes of `config` this selector picks. Names that are neither a node
+    /// nor a group, and patterns or labels matching no node, are added to
+    /// `unknown`.
+    pub fn select<'a>(
+        &self,
+        config: &'a ServerConfig,
+        unknown: &mut Vec<String>,
+    ) -> BTreeSet<&'a String> {
+        match self {
+            Selector::Name(name) => {
+                if let Some((name, _)) = config.nodes.get_key_value(name) {
+                    BTreeSet::from([name])
+                } else if config.is_group(name) {
+                    config.group_nodes(name)
+                } else {
+                    unknown.push(name.clone());
+                    BTreeSet::new()
+                }
+            }
+            Selector::Glob(pattern) => matching(config, pattern, unknown, |name, _| {
+                glob_match(pattern, name)
+            }),
+            Selector::Regex(source, regex) => {
+                matching(config, &format!("/{}/", source), unknown, |name, _| {
+                    regex.is_match(name)
+                })
+            }
+            Selector::Label { key, value } => {
+                matching(config, &format!("{}={}", key, value), unknown, |_, node| {
+                    node.labels
+                        .get(key)
+                        .is_some_and(|label| glob_match(value, label))
+                })
+            }
+            Selector::Union(selectors) => selectors
+                .iter()
+                .flat_map(|selector| selector.select(config, unknown))
+                .collect(),
+            Selector::Intersection(selectors) => {
+                let mut sets = selectors
+                    .iter()
+                    .map(|selector| selector.select(config, unknown))
+                    .collect::<Vec<_>>()
+                    .into_iter();
+                let first = sets.next().unwrap_or_default();
+                sets.fold(first, |nodes, set| &nodes & &set)
+            }
+            Selector::Not(selector) => {
+                let excluded = selector.select(config, unknown);
+                config
+                    .nodes
+                    .keys()
+                    .filter(|name| !excluded.contains(name))
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Nodes of `config` for which `matches` holds, `term` being added to
+/// `unknown` if there are none.
+fn matching<'a>(
+    config: &'a ServerConfig,
+    term: &str,
+    unknown: &mut Vec<String>,
+    matches: impl Fn(&String, &Node) -> bool,
+) -> BTreeSet<&'a String> {
+    let nodes: BTreeSet<&String> = config
+        .nodes
+        .iter()
+        .filter(|(name, node)| matches(name, node))
+        .map(|(name, _)| name)
+        .collect();
+    if nodes.is_empty() {
+        unknown.push(term.to_string());
+    }
+
+    nodes
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn union(&mut self) -> Result<Selector, SelectorError> {
+        let mut selectors = vec![self.intersection()?];
+        while self.eat(',') {
+            selectors.push(self.intersection()?);
+        }
+
+        Ok(match selectors.len() {
+            1 => selectors.remove(0),
+            _ => Selector::Union(selectors),
+        })
+    }
+
+    fn intersection(&mut self) -> Result<Selector, SelectorError> {
+        let mut selectors = vec![self.unary()?];
+        while self.eat('&') {
+            selectors.push(self.unary()?);
+        }
+
+        Ok(match selectors.len() {
+            1 => selectors.remove(0),
+            _ => Selector::Intersection(selectors),
+        })
+    }
+
+    fn unary(&mut self) -> Result<Selector, SelectorError> {
+        if self.eat('!') {
+            return Ok(Selector::Not(Box::new(self.unary()?)));
+        }
+        if self.eat('(') {
+            let selector = self.union()?;
+            if !self.eat(')') {
+                return Err(self.error("expected ')'"));
+            }
+            return Ok(selector);
+        }
+
+        self.term()
+    }
+
+    fn term(&mut self) -> Result<Selector, SelectorError> {
+        self.skip_spaces();
+        if self.eat('/') {
+            return self.regex();
+        }
+
+        let start = self.pos;
+        while self.peek().is_some_and(|c| !OPERATORS.contains(&c)) {
+            self.pos += 1;
+        }
+        let term: String = self.chars[start..self.pos].iter().collect();
+        let term = term.trim();
+
+        if term.is_empty() {
+            Err(self.error("expected a node, a group, a pattern or a label"))
+        } else if let Some((key, value)) = term.split_once('=') {
+            Ok(Selector::Label {
+                key: key.trim().to_string(),
+                value: value.trim().to_string(),
+            })
+        } else if term.contains(['*', '?']) {
+            Ok(Selector::Glob(term.to_string()))
+        } else {
+            Ok(Selector::Name(term.to_string()))
+        }
+    }
+
+    /// Reads a regex up to its closing slash, `\/` standing for a slash.
+    fn regex(&mut self) -> Result<Selector, SelectorError> {
+        let mut source = String::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.error("unterminated regex")),
+                Some('/') => break,
+                Some('\\') if self.chars.get(self.pos + 1) == Some(&'/') => {
+                    source.push('/');
+                    self.pos += 1;
+                }
+                Some(c) => source.push(c),
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+
+        match Regex::new(&format!("^(?:{})$", source)) {
+            Ok(regex) => Ok(Selector::Regex(source, regex)),
+            // Its last line has the reason, the others draw where it is.
+            Err(err) => Err(SelectorError::Syntax(format!(
+                "invalid regex /{}/: {}",
+                source,
+                err.to_string().lines().last().unwrap_or_default()
+            ))),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    /// Consumes `c` if it comes next, spaces aside.
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_spaces();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn error(&self, reason: &str) -> SelectorError {
+        SelectorError::Syntax(format!(
+            "{} at position {} of '{}'",
+            reason,
+            self.pos + 1,
+            self.chars.iter().collect::<String>()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ServerConfig {
+        let mut config = ServerConfig::default();
+        for (name, role) in [
+            ("web1", "web"),
+            ("web2", "web"),
+            ("db1", "db"),
+            ("db2", "db"),
+        ] {
+            let node = format!("host = \"10.0.0.1\"\nlabels = {{ role = \"{}\" }}", role);
+            config
+                .nodes
+                .insert(name.to_string(), toml::from_str(&node).unwrap());
+        }
+        config.groups.insert(
+            "dbs".to_string(),
+            vec!["db1".to_string(), "db2".to_string()],
+        );
+
+        config
+    }
+
+    fn select(selector: &str) -> Vec<String> {
+        config()
+            .select_nodes(&[selector.to_string()])
+            .unwrap()
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    fn syntax_error(selector: &str) -> String {
+        match Selector::parse(selector) {
+            Err(SelectorError::Syntax(reason)) => reason,
+            other => panic!("{:?} parsed as {:?}", selector, other),
+        }
+    }
+
+    #[test]
+    fn intersection_binds_tighter_than_union() {
+        assert_eq!(select("web1,db1&role=db"), ["db1", "web1"]);
+        assert_eq!(select("web1 , web2 & role=db"), ["web1"]);
+        assert_eq!(select("(web1,db1)&role=db"), ["db1"]);
+        assert_eq!(select("!web1&web*"), ["web2"]);
+        assert_eq!(
+            select("dbs&/db[0-9]+/,web?"),
+            ["db1", "db2", "web1", "web2"]
+        );
+    }
+
+    #[test]
+    fn negations_nest() {
+        assert_eq!(select("!!web1"), ["web1"]);
+        assert_eq!(select("!(web*,db1)"), ["db2"]);
+        assert_eq!(select("!(!dbs)"), ["db1", "db2"]);
+        assert_eq!(select("role=*&!(role=web&!web2)"), ["db1", "db2", "web2"]);
+    }
+
+    #[test]
+    fn unterminated_regex_is_an_error() {
+        let reason = syntax_error("web1,/db[0-9]+");
+        assert!(reason.starts_with("unterminated regex"), "{}", reason);
+
+        let reason = syntax_error("/db(/");
+        assert!(reason.starts_with("invalid regex /db(/"), "{}", reason);
+    }
+
+    #[test]
+    fn unbalanced_parentheses_are_errors() {
+        assert_eq!(
+            syntax_error("(web1,db1"),
+            "expected ')' at position 10 of '(web1,db1'"
+        );
+        assert_eq!(
+            syntax_error("web1)"),
+            "unexpected ')' at position 5 of 'web1)'"
+        );
+        assert!(syntax_error("()").starts_with("expected a node"));
+    }
+
+    #[test]
+    fn terms_selecting_nothing_are_unknown() {
+        let selectors = ["web1,nope", "cache*", "role=cache", "/x+/"].map(String::from);
+        match config().select_nodes(&selectors) {
+            Err(SelectorError::Unknown(terms)) => {
+                assert_eq!(terms, ["nope", "cache*", "role=cache", "/x+/"])
+            }
+            other => panic!("unexpected selection {:?}", other),
+        }
+
+        // Terms selecting nothing are unknown even where they don't matter.
+        assert!(config()
+            .select_nodes(&["web1,nope&db1".to_string()])
+            .is_err());
+    }
+}
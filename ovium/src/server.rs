@@ -1,96 +1,504 @@
-use crate::error::{ConfigError, Error, ErrorKind, OviumError};
+use crate::acl::{self, AclRule, Peer};
+use crate::audit::{AuditConfig, AuditLog, AuditRecord, AuditTrail};
+use crate::error::{
+    ConfigError, Error, ErrorKind, HostKeyError, OviumError, RequestError, SelectorError,
+};
+use crate::handlers::rerun_request;
+use crate::history::{History, HistoryConfig};
+use crate::inventory::{Inventory, InventoryConfig};
+use crate::jobs::JobTable;
+use crate::logging::{self, LogConfig, LogContext};
+use crate::pool::{PoolConfig, SessionPool};
+use crate::selector::Selector;
 use crate::types::*;
-use crossbeam_channel::unbounded;
+use crossbeam_channel::{bounded, unbounded, RecvTimeoutError};
 use crossbeam_utils::thread;
-use log::{error, info};
+use libc::c_int;
+use log::{error, info, warn};
 use serde::Deserialize;
-use signal_hook::{iterator::Signals, SIGINT};
-use ssh2::Session;
-use std::collections::HashMap;
-use std::fs::File;
+use signal_hook::{iterator::Signals, SIGHUP, SIGINT, SIGTERM};
+use ssh2::{Channel, CheckResult, HashType, KnownHostFileKind, Session};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::env;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
 use std::io::prelude::*;
-use std::io::{self, BufRead, BufReader};
-use std::net::TcpStream;
+use std::io::{self, BufReader, BufWriter};
+use std::net::{Ipv4Addr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
-use std::path::Path;
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 pub struct Server<'a> {
     socket_path: &'a str,
-    config: ServerConfig,
+    config_path: &'a str,
+    /// Swapped as a whole on reload, requests keep the snapshot they started
+    /// with.
+    config: RwLock<Arc<ServerConfig>>,
     listener: UnixListener,
+    /// Device and inode of the socket we bound, so that we never remove a
+    /// socket that replaced ours.
+    socket_id: (u64, u64),
+    pool: SessionPool,
+    audit: AuditLog,
+    shutdown: Shutdown,
+    /// Id of the last request, tagging its log lines and audit record.
+    last_request_id: AtomicU64,
+    jobs: JobTable,
+    history: History,
+    inventory: Inventory,
+    /// The config read from the files, which the inventory nodes are added
+    /// to. Held while the config is being replaced.
+    base_config: Mutex<ServerConfig>,
 }
 
-#[derive(Deserialize, Debug)]
+/// Where the daemon is in its shutdown, shared with the request handlers.
+#[derive(Default)]
+struct Shutdown {
+    /// Set once no new connection is accepted.
+    stopping: AtomicBool,
+    /// Set once running requests must give up.
+    cancelled: AtomicBool,
+    /// Number of connections being handled.
+    active: AtomicUsize,
+}
+
+/// The config, merged from the files of the config directory, see
+/// `config_files`, and from the inventory sources.
+#[derive(Debug, Default, Clone)]
 pub struct ServerConfig {
     pub nodes: HashMap<String, Node>,
-    #[serde(default)]
     pub groups: HashMap<String, Vec<String>>,
+    pub defaults: NodeDefaults,
+    pub host_keys: HostKeyConfig,
+    pub pool: PoolConfig,
+    pub server: DaemonConfig,
+    /// Who may do what through the control socket, anyone who can open it
+    /// when empty.
+    pub acl: Vec<AclRule>,
+    pub audit: AuditConfig,
+    pub log: LogConfig,
+    pub history: HistoryConfig,
+    pub inventory: Vec<InventoryConfig>,
+    /// Where each node, group and section was defined, to report the ones
+    /// defined twice.
+    origins: HashMap<String, String>,
+}
+
+/// One of the files the config is split across. Its nodes, groups and ACL
+/// rules add up with the other files', its sections can't be in another file.
+#[derive(Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    nodes: HashMap<String, Node>,
+    #[serde(default)]
+    groups: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    acl: Vec<AclRule>,
+    #[serde(default)]
+    inventory: Vec<InventoryConfig>,
+    defaults: Option<NodeDefaults>,
+    host_keys: Option<HostKeyConfig>,
+    pool: Option<PoolConfig>,
+    server: Option<DaemonConfig>,
+    audit: Option<AuditConfig>,
+    log: Option<LogConfig>,
+    history: Option<HistoryConfig>,
+}
+
+/// Settings of the daemon itself, as opposed to the nodes it manages.
+#[derive(Deserialize, Debug, Clone)]
+pub struct DaemonConfig {
+    /// Seconds running requests get to finish once a shutdown is asked for,
+    /// before they're cancelled.
+    #[serde(default = "default_shutdown_grace")]
+    pub shutdown_grace: u64,
+    /// Permissions of the socket, e.g. `0o660`. Left to the umask if unset.
+    pub socket_mode: Option<u32>,
+    /// Group owning the socket, so that its members can use it along with
+    /// `socket_mode`.
+    pub socket_group: Option<String>,
+}
+
+/// Settings applied to every node that doesn't set its own.
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct NodeDefaults {
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    pub auth: Option<Auth>,
 }
 
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct HostKeyConfig {
+    #[serde(default = "default_known_hosts")]
+    pub known_hosts: PathBuf,
+    #[serde(default)]
+    pub policy: HostKeyPolicy,
+}
+
+/// What to do with a node's host key: `Strict` only accepts keys already in
+/// known_hosts, `AcceptNew` also records the key of nodes not in there yet,
+/// and `Off` skips the check entirely.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostKeyPolicy {
+    #[default]
+    Strict,
+    AcceptNew,
+    Off,
+}
+
+/// Everything needed to open a session on a node, resolved from the request,
+/// the node and the config sections.
+pub struct SshSettings<'a> {
+    pub timeouts: Timeouts,
+    pub auth: &'a Auth,
+    pub host_keys: &'a HostKeyConfig,
+    /// Nodes to hop through, by name, with how to authenticate on them.
+    pub jump_hosts: Vec<(&'a str, &'a Node, &'a Auth)>,
+}
+
+const LIBSSH2_ERROR_TIMEOUT: i32 = -9;
+/// Longest a jump host relay sleeps without checking its session, which may
+/// have buffered data its socket doesn't tell about.
+const RELAY_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the inventory sources are checked for being due for a refresh.
+const INVENTORY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Longest a client may take to send its handshake, then its request, so
+/// that an idle connection can't hold a shutdown off.
+const CLIENT_READ_TIMEOUT: Duration = Duration::from_secs(10);
+static DEFAULT_AUTH: Auth = Auth::Agent;
+/// Held while known_hosts is read or appended to, so that two nodes accepted
+/// at the same time can't clobber each other's entry.
+static KNOWN_HOSTS_LOCK: Mutex<()> = Mutex::new(());
+
 impl ServerConfig {
     pub fn is_group(&self, name: &str) -> bool {
         self.groups.contains_key(name)
     }
+
+    /// Nodes of group `name`, along with the nodes of the groups it contains.
+    pub fn group_nodes(&self, name: &str) -> BTreeSet<&String> {
+        let mut nodes = BTreeSet::new();
+        self.expand_group(name, &mut HashSet::new(), &mut nodes);
+
+        nodes
+    }
+
+    fn expand_group<'a>(
+        &'a self,
+        name: &str,
+        expanded: &mut HashSet<&'a str>,
+        nodes: &mut BTreeSet<&'a String>,
+    ) {
+        let (name, members) = match self.groups.get_key_value(name) {
+            Some(group) => group,
+            None => return,
+        };
+        // Also keeps a cycle, which validate_config rejects, from looping.
+        if !expanded.insert(name) {
+            return;
+        }
+
+        for member in members {
+            match self.nodes.get_key_value(member) {
+                Some((node_name, _)) => {
+                    nodes.insert(node_name);
+                }
+                None => self.expand_group(member, expanded, nodes),
+            }
+        }
+    }
+
+    /// Returns the sorted node names the union of `selectors` picks, or the
+    /// terms selecting nothing if there are some.
+    pub fn select_nodes(&self, selectors: &[String]) -> Result<Vec<&String>, SelectorError> {
+        let mut nodes = BTreeSet::new();
+        let mut unknown = Vec::new();
+        for selector in selectors {
+            nodes.extend(Selector::parse(selector)?.select(self, &mut unknown));
+        }
+
+        if unknown.is_empty() {
+            Ok(nodes.into_iter().collect())
+        } else {
+            Err(SelectorError::Unknown(unknown))
+        }
+    }
+
+    /// Like `select_nodes`, for selectors already validated: invalid ones
+    /// select nothing, and unknown terms are skipped.
+    pub fn resolve_nodes(&self, selectors: &[String]) -> Vec<&String> {
+        let mut nodes = BTreeSet::new();
+        for selector in selectors {
+            if let Ok(selector) = Selector::parse(selector) {
+                nodes.extend(selector.select(self, &mut Vec::new()));
+            }
+        }
+
+        nodes.into_iter().collect()
+    }
+
+    pub fn ssh_settings<'a>(
+        &'a self,
+        node: &'a Node,
+        request_timeouts: Timeouts,
+    ) -> SshSettings<'a> {
+        SshSettings {
+            timeouts: request_timeouts
+                .or(node.timeouts)
+                .or(self.defaults.timeouts)
+                .or(Timeouts::BUILTIN),
+            auth: self.auth(node),
+            host_keys: &self.host_keys,
+            jump_hosts: node
+                .proxy_jump
+                .iter()
+                .filter_map(|name| self.nodes.get_key_value(name))
+                .map(|(name, jump_host)| (name.as_str(), jump_host, self.auth(jump_host)))
+                .collect(),
+        }
+    }
+
+    fn auth<'a>(&'a self, node: &'a Node) -> &'a Auth {
+        node.auth
+            .as_ref()
+            .or(self.defaults.auth.as_ref())
+            .unwrap_or(&DEFAULT_AUTH)
+    }
+}
+
+impl Default for HostKeyConfig {
+    fn default() -> Self {
+        HostKeyConfig {
+            known_hosts: default_known_hosts(),
+            policy: HostKeyPolicy::default(),
+        }
+    }
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            shutdown_grace: default_shutdown_grace(),
+            socket_mode: None,
+            socket_group: None,
+        }
+    }
+}
+
+fn default_shutdown_grace() -> u64 {
+    30
+}
+
+fn default_known_hosts() -> PathBuf {
+    let home = env::var_os("HOME").unwrap_or_else(|| "/root".into());
+    Path::new(&home).join(".ssh/known_hosts")
 }
 
 impl Server<'_> {
     pub fn new<'a>(socket_path: &'a str, config_path: &'a str) -> Result<Server<'a>, OviumError> {
-        let server_config = ServerConfig::new(Path::new(config_path))?;
-        let listener =
-            UnixListener::bind(socket_path).map_err(|err| (ErrorKind::Bind, err.into()))?;
-        listener
-            .set_nonblocking(true)
+        let base_config = ServerConfig::new(Path::new(config_path))?;
+        let inventory = Inventory::default().with_sources(&base_config.inventory);
+        let server_config = load_inventory(&base_config, &inventory)?;
+        let (listener, socket_id) = bind(socket_path, &server_config.server)
             .map_err(|err| (ErrorKind::Bind, err.into()))?;
 
+        let audit =
+            AuditLog::new(&server_config.audit).map_err(|err| (ErrorKind::Audit, err.into()))?;
+        let history =
+            History::new(&server_config.history).map_err(|err| (ErrorKind::History, err.into()))?;
+        let last_request_id = history
+            .last_id()
+            .map_err(|err| (ErrorKind::History, err.into()))?;
+
         Ok(Server {
             socket_path,
-            config: server_config,
+            config_path,
+            socket_id,
+            audit,
+            pool: SessionPool::new(server_config.pool.clone()),
+            config: RwLock::new(Arc::new(server_config)),
             listener,
+            shutdown: Shutdown::default(),
+            last_request_id: AtomicU64::new(last_request_id),
+            jobs: JobTable::default(),
+            history,
+            inventory,
+            base_config: Mutex::new(base_config),
         })
     }
 
+    /// The current configuration.
+    pub fn config(&self) -> Arc<ServerConfig> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Reads and validates the config directory again, then swaps it in for
+    /// the requests to come. An invalid config is rejected and the current
+    /// one kept.
+    pub fn reload(&self) -> Result<Arc<ServerConfig>, OviumError> {
+        let mut base_config = self.base_config.lock().unwrap();
+        // Whatever may fail is done aside, so that a rejected config leaves
+        // the current one whole.
+        let new_base_config = ServerConfig::new(Path::new(self.config_path))?;
+        let inventory = self.inventory.with_sources(&new_base_config.inventory);
+        let config = Arc::new(load_inventory(&new_base_config, &inventory)?);
+        let audit = self
+            .audit
+            .prepare(&config.audit)
+            .map_err(|err| (ErrorKind::Audit, err.into()))?;
+        let history = self
+            .history
+            .prepare(&config.history)
+            .map_err(|err| (ErrorKind::History, err.into()))?;
+
+        self.inventory.replace(inventory);
+        self.audit.apply(audit);
+        self.history.apply(history);
+        self.pool.set_config(config.pool.clone());
+        logging::set_level(config.log.level);
+        *base_config = new_base_config;
+        *self.config.write().unwrap() = config.clone();
+        info!(
+            "Reloaded configuration from {}: {} nodes, {} groups",
+            self.config_path,
+            config.nodes.len(),
+            config.groups.len()
+        );
+
+        Ok(config)
+    }
+
+    /// Reads the inventory sources due for a refresh and, if their nodes
+    /// changed, swaps in a config with them. An invalid result is rejected
+    /// and the current config and inventory data kept.
+    fn refresh_inventory(&self) {
+        // Read aside, so that a slow source doesn't hold reloads up.
+        let snapshot = self.inventory.snapshot();
+        let refreshed = snapshot.refresh(false);
+        let base_config = self.base_config.lock().unwrap();
+        match refreshed {
+            Ok(true) => (),
+            Ok(false) => {
+                self.inventory.update(snapshot, true);
+                return;
+            }
+            Err(err) => {
+                error!("Inventory refresh failed: {}", err);
+                self.inventory.update(snapshot, false);
+                return;
+            }
+        }
+
+        let merged = base_config.with_inventory(&snapshot);
+        // The snapshot is stale if a reload swapped the sources meanwhile.
+        if !self.inventory.update(snapshot, merged.is_ok()) {
+            return;
+        }
+        match merged {
+            Ok(config) => {
+                info!(
+                    "Refreshed the inventory: {} nodes, {} groups",
+                    config.nodes.len(),
+                    config.groups.len()
+                );
+                *self.config.write().unwrap() = Arc::new(config);
+            }
+            Err(err) => error!(
+                "Refreshed inventory is invalid, keeping the current configuration: {}",
+                err
+            ),
+        }
+    }
+
+    pub fn pool(&self) -> &SessionPool {
+        &self.pool
+    }
+
+    pub fn jobs(&self) -> &JobTable {
+        &self.jobs
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    /// Whether running requests must stop, the shutdown grace period being
+    /// over.
+    pub fn is_cancelled(&self) -> bool {
+        self.shutdown.cancelled.load(Ordering::SeqCst)
+    }
+
     pub fn run(&self) -> Result<(), OviumError> {
+        let signal_pipe = SignalPipe::new(&[SIGINT, SIGTERM, SIGHUP]).unwrap();
         thread::scope(|s| -> Result<(), OviumError> {
-            let (signal_sender, signal_receiver) = unbounded();
-            let signals = Signals::new(&[SIGINT]).unwrap();
-
+            // Dropped once we stop accepting connections, which ends the pool
+            // maintenance and inventory refresh threads.
+            let (maintenance_stop, pool_stop_receiver) = unbounded::<()>();
+            let inventory_stop_receiver = pool_stop_receiver.clone();
             s.spawn(move |_| {
-                for sig in signals.forever() {
-                    println!("Received signal {:?}", sig);
-                    if sig == signal_hook::SIGINT {
-                        signal_sender.clone().send(sig).unwrap();
-                        break;
-                    }
+                // The interval is read every time, a reload may change it.
+                while let Err(RecvTimeoutError::Timeout) =
+                    pool_stop_receiver.recv_timeout(self.pool.keepalive_interval())
+                {
+                    self.pool.maintain();
                 }
             });
-
-            for stream in self.listener.incoming() {
-                if signal_receiver.clone().try_recv().is_ok() {
-                    break;
+            s.spawn(move |_| {
+                while let Err(RecvTimeoutError::Timeout) =
+                    inventory_stop_receiver.recv_timeout(INVENTORY_CHECK_INTERVAL)
+                {
+                    self.refresh_inventory();
                 }
+            });
 
-                match stream {
-                    Ok(stream) => {
-                        /* connection succeeded */
-                        let stream_receiver = signal_receiver.clone();
-                        s.spawn::<_, Result<(), OviumError>>(move |_| {
-                            self.handle_client(stream, stream_receiver)
-                                .map_err(|err| (ErrorKind::Handle, err))?;
-                            Ok(())
-                        });
+            // Sleeps until a client connects or a signal comes in.
+            let fds = [self.listener.as_raw_fd(), signal_pipe.as_raw_fd()];
+            'accept: while !self.shutdown.stopping.load(Ordering::SeqCst) {
+                let readable =
+                    poll_readable(&fds, None).map_err(|err| (ErrorKind::Handle, err.into()))?;
+                if readable[1] {
+                    for sig in signal_pipe.take() {
+                        self.handle_signal(sig);
                     }
-                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                        std::thread::sleep(Duration::from_millis(500));
-                        continue;
+                    if self.shutdown.stopping.load(Ordering::SeqCst) {
+                        break;
                     }
+                }
+                if !readable[0] {
+                    continue;
+                }
 
-                    Err(_) => {
-                        /* connection failed */
-                        break;
+                // Several clients may be waiting behind a single wakeup.
+                loop {
+                    match self.listener.accept() {
+                        Ok((stream, _)) => {
+                            /* connection succeeded */
+                            self.shutdown.active.fetch_add(1, Ordering::SeqCst);
+                            s.spawn::<_, Result<(), OviumError>>(move |_| {
+                                let handled = self.handle_client(stream);
+                                self.shutdown.active.fetch_sub(1, Ordering::SeqCst);
+                                handled.map_err(|err| (ErrorKind::Handle, err))?;
+                                Ok(())
+                            });
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(err) => {
+                            /* connection failed */
+                            error!("Failed to accept a connection: {}", err);
+                            break 'accept;
+                        }
                     }
                 }
             }
+            self.drain(&signal_pipe);
+            drop(maintenance_stop);
             Ok(())
         })
         .unwrap()?;
@@ -98,115 +506,1180 @@ impl Server<'_> {
         Ok(())
     }
 
-    fn handle_client(
-        &self,
-        stream: UnixStream,
-        _signal_receiver: crossbeam_channel::Receiver<i32>,
-    ) -> Result<(), Error> {
+    /// SIGHUP reloads the config. Otherwise a first signal stops the accept
+    /// loop, a second one cancels the requests still running without waiting
+    /// for the grace period.
+    fn handle_signal(&self, sig: c_int) {
+        if sig == SIGHUP {
+            info!("Received signal {}, reloading configuration", sig);
+            if let Err(err) = self.reload() {
+                error!(
+                    "Configuration reload failed, keeping the current one: {}",
+                    err
+                );
+            }
+        } else if !self.shutdown.stopping.swap(true, Ordering::SeqCst) {
+            info!("Received signal {}, shutting down", sig);
+        } else {
+            warn!("Received signal {} again, cancelling running requests", sig);
+            self.shutdown.cancelled.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Gives the running requests `shutdown_grace` seconds to finish, then
+    /// cancels them. Signals are still handled meanwhile.
+    fn drain(&self, signal_pipe: &SignalPipe) {
+        let grace = Duration::from_secs(self.config().server.shutdown_grace);
+        let started = Instant::now();
+        let active = self.shutdown.active.load(Ordering::SeqCst);
+        if active > 0 {
+            info!(
+                "Waiting up to {}s for {} running request(s)",
+                grace.as_secs(),
+                active
+            );
+        }
+
+        while self.shutdown.active.load(Ordering::SeqCst) > 0 && !self.is_cancelled() {
+            if started.elapsed() >= grace {
+                warn!(
+                    "Grace period over, cancelling {} running request(s)",
+                    self.shutdown.active.load(Ordering::SeqCst)
+                );
+                self.shutdown.cancelled.store(true, Ordering::SeqCst);
+                break;
+            }
+
+            let wait = Duration::from_millis(50);
+            if let Ok([true]) = poll_readable(&[signal_pipe.as_raw_fd()], Some(wait)) {
+                for sig in signal_pipe.take() {
+                    self.handle_signal(sig);
+                }
+            }
+        }
+    }
+
+    fn handle_client(&self, stream: UnixStream) -> Result<(), Error> {
+        let request_id = self.last_request_id.fetch_add(1, Ordering::SeqCst) + 1;
+        logging::set_context(LogContext {
+            request_id: Some(request_id),
+            node: None,
+        });
+        let peer = Peer::from_stream(&stream)?;
+        stream.set_read_timeout(Some(CLIENT_READ_TIMEOUT))?;
         let mut reader = BufReader::new(&stream);
 
-        loop {
-            let mut resp = Vec::new();
-            let read_bytes = reader.read_until(b'\n', &mut resp);
-            match read_bytes {
-                Ok(read_bytes) => {
-                    if read_bytes == 0 {
-                        info!("connection closed by remote");
-                    } else {
-                        let recv_request = Request::decode(&resp)?;
-                        let handler = match recv_request {
-                            Request::Cmd(inner_req) => {
-                                ServerHandler::<CmdRequest>::new(stream, inner_req)
-                            }
-                        };
-                        handler.validate_request(&self.config)?;
-                        handler.handle(&self.config)?;
-                    };
-                    break;
-                }
-                Err(err) => match err.kind() {
-                    io::ErrorKind::Interrupted => continue,
-                    _ => break,
-                },
+        // A failed handshake is audited, a connection closed before any
+        // request isn't.
+        let handshake = match Request::read_from(&mut reader) {
+            Ok(None) => {
+                info!("connection closed by remote");
+                return Ok(());
             }
+            Ok(Some(Request::Hello(client_hello))) => Server::handshake(&stream, &client_hello),
+            Ok(Some(_)) => Err(RequestError::HandshakeRequired.into()),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = handshake {
+            let mut record = AuditRecord::handshake(request_id, &peer);
+            record.finish(&AuditTrail::default(), Some(err.to_string()));
+            self.audit(&record);
+            return Err(err);
         }
-        Ok(())
+
+        let recv_request = match Request::read_from(&mut reader)? {
+            None => {
+                info!("connection closed by remote");
+                return Ok(());
+            }
+            Some(recv_request) => recv_request,
+        };
+        // Nothing more is read, handlers may take as long as their request.
+        stream.set_read_timeout(None)?;
+        let config = self.config();
+        let recv_request = match recv_request {
+            Request::Rerun(rerun) => match rerun_request(self, &stream, &config, &peer, &rerun) {
+                Ok(cmd_request) => Request::Cmd(cmd_request),
+                Err(err) => {
+                    let rerun = Request::Rerun(rerun);
+                    let mut record = AuditRecord::start(request_id, &peer, &rerun, &config);
+                    record.finish(&AuditTrail::default(), Some(err.to_string()));
+                    self.audit(&record);
+                    return Err(err);
+                }
+            },
+            recv_request => recv_request,
+        };
+        let access = acl::authorize(&config, &peer, &recv_request);
+        let mut record = AuditRecord::start(request_id, &peer, &recv_request, &config);
+        // Written right away, so that a request still leaves a trace if the
+        // daemon dies while it runs.
+        self.audit(&record);
+        let trail = AuditTrail::default();
+        let handled = match recv_request {
+            Request::Cmd(inner_req) => self.dispatch(
+                ServerHandler::new(stream, inner_req, request_id, peer, trail.clone()),
+                &config,
+                access,
+            ),
+            Request::Status(inner_req) => self.dispatch(
+                ServerHandler::new(stream, inner_req, request_id, peer, trail.clone()),
+                &config,
+                access,
+            ),
+            Request::Upload(inner_req) => self.dispatch(
+                ServerHandler::new(stream, inner_req, request_id, peer, trail.clone()),
+                &config,
+                access,
+            ),
+            Request::Download(inner_req) => self.dispatch(
+                ServerHandler::new(stream, inner_req, request_id, peer, trail.clone()),
+                &config,
+                access,
+            ),
+            Request::Reload(inner_req) => self.dispatch(
+                ServerHandler::new(stream, inner_req, request_id, peer, trail.clone()),
+                &config,
+                access,
+            ),
+            Request::Submit(inner_req) => self.dispatch(
+                ServerHandler::new(stream, inner_req, request_id, peer, trail.clone()),
+                &config,
+                access,
+            ),
+            Request::Job(inner_req) => self.dispatch(
+                ServerHandler::new(stream, inner_req, request_id, peer, trail.clone()),
+                &config,
+                access,
+            ),
+            Request::History(inner_req) => self.dispatch(
+                ServerHandler::new(stream, inner_req, request_id, peer, trail.clone()),
+                &config,
+                access,
+            ),
+            Request::Hello(_) => Err(RequestError::HandshakeRequired.into()),
+            Request::Rerun(_) => unreachable!("reruns are turned into command requests"),
+        };
+
+        record.finish(&trail, handled.as_ref().err().map(Error::to_string));
+        self.audit(&record);
+
+        handled
     }
 
-    pub fn execute_cmd(node: &Node, cmd: String) -> Result<SshSuccess, Error> {
-        let node_addr = format!("{}:{}", node.ip, node.port);
-        let tcp = TcpStream::connect(node_addr)?;
-        let mut sess = Session::new()?;
-        sess.set_tcp_stream(tcp);
-        sess.handshake()?;
-        sess.userauth_agent(&node.user)?;
-        let mut channel = sess.channel_session()?;
-        channel.exec(&cmd)?;
-        let mut stdout_string = String::new();
-        let mut stderr_string = String::new();
-        channel.read_to_string(&mut stdout_string)?;
-        channel.stderr().read_to_string(&mut stderr_string)?;
-        channel.wait_close()?;
-        let stdout_string = stdout_string.replace("\n", "\\n");
-        let stderr_string = stderr_string.replace("\n", "\\n");
-
-        let stderr = if stderr_string.is_empty() {
-            None
-        } else {
-            Some(stderr_string)
+    /// Appends `record` to the audit log. The request goes on if it fails.
+    fn audit(&self, record: &AuditRecord) {
+        if let Err(err) = self.audit.write(record) {
+            error!("Failed to write to the audit log: {}", err);
+        }
+    }
+
+    /// Handles the request if `access` allows it and it's valid. Access is
+    /// checked first, so that a forbidden client can't learn which nodes and
+    /// groups exist from the validation errors.
+    fn dispatch<T>(
+        &self,
+        handler: ServerHandler<T>,
+        config: &ServerConfig,
+        access: Result<(), String>,
+    ) -> Result<(), Error>
+    where
+        ServerHandler<T>: ServerActions<T>,
+    {
+        if let Err(reason) = access {
+            warn!("Forbidden: {}", reason);
+            let response = Response::Error(ResponseError::Forbidden(reason.clone()));
+            let mut writer = BufWriter::new(&handler.stream);
+            writer.write_all(&response.encode()?)?;
+
+            return Err(RequestError::Forbidden(reason).into());
+        }
+        handler.validate_request(config)?;
+        handler.handle(self, config)
+    }
+
+    /// Answers the client's `Hello` with the version picked for the
+    /// connection, or with an error if there's none both sides speak.
+    fn handshake(stream: &UnixStream, client_hello: &Hello) -> Result<(), Error> {
+        let server_hello = Hello::default();
+        let mut writer = BufWriter::new(stream);
+
+        match server_hello.negotiate(client_hello) {
+            Some(protocol_version) => {
+                let response = Response::Hello(Hello {
+                    protocol_version,
+                    ..server_hello
+                });
+                writer.write_all(&response.encode()?)?;
+                Ok(())
+            }
+            None => {
+                error!(
+                    "Client speaks protocol v{}-v{}, we speak v{}-v{}",
+                    client_hello.min_protocol_version,
+                    client_hello.protocol_version,
+                    server_hello.min_protocol_version,
+                    server_hello.protocol_version
+                );
+                let response = Response::Error(ResponseError::IncompatibleProtocol {
+                    client: (
+                        client_hello.min_protocol_version,
+                        client_hello.protocol_version,
+                    ),
+                    server: (
+                        server_hello.min_protocol_version,
+                        server_hello.protocol_version,
+                    ),
+                });
+                writer.write_all(&response.encode()?)?;
+                Err(RequestError::IncompatibleProtocol(client_hello.protocol_version).into())
+            }
+        }
+    }
+
+    /// Runs `cmd` on `node`, reusing a pooled session when there's one.
+    /// When an `output` callback is given, complete lines are handed to it as
+    /// soon as they are read and are left out of the returned `SshSuccess`.
+    /// The command is aborted once `cancelled` returns true.
+    pub fn execute_cmd(
+        &self,
+        node_name: &str,
+        node: &Node,
+        cmd: &str,
+        settings: &SshSettings,
+        cancelled: &dyn Fn() -> bool,
+        output: Option<&mut dyn FnMut(OutputKind, String)>,
+    ) -> Result<SshSuccess, Error> {
+        let pooled = self
+            .pool
+            .take(node_name, node, settings)
+            .and_then(|sess| sess.channel_session().ok().map(|channel| (sess, channel)));
+        let (sess, channel) = match pooled {
+            Some(pooled) => pooled,
+            None => {
+                let sess = open_session(node, settings)?;
+                self.pool.opened(node_name);
+                let channel = sess.channel_session()?;
+                (sess, channel)
+            }
         };
 
-        let stdout = if stdout_string.is_empty() {
-            None
-        } else {
-            Some(stdout_string)
+        let ssh_success = run_cmd(
+            &sess,
+            channel,
+            &node_command(node, cmd),
+            settings.timeouts.exec,
+            cancelled,
+            output,
+        )?;
+        self.pool.put(node_name, node, settings, sess);
+
+        Ok(ssh_success)
+    }
+
+    /// Copies the request's contents to `node` over scp, then hands it to
+    /// its owner. Returns the number of bytes written. The transfer is
+    /// aborted once `cancelled` returns true.
+    pub fn upload(
+        &self,
+        node_name: &str,
+        node: &Node,
+        req: &UploadRequest,
+        settings: &SshSettings,
+        cancelled: &dyn Fn() -> bool,
+    ) -> Result<u64, Error> {
+        let exec_timeout = settings.timeouts.exec;
+        let sess = self.session(node_name, node, settings)?;
+        if let Some(seconds) = exec_timeout {
+            sess.set_timeout((seconds * 1000) as u32);
+        }
+
+        let started = Instant::now();
+        let size = req.contents.len() as u64;
+        let mut remote = sess
+            .scp_send(Path::new(&req.destination), req.mode, size, None)
+            .map_err(|err| transfer_error(err.into(), exec_timeout))?;
+        // Written without blocking, so that a stalled node can't hold off a
+        // cancel.
+        sess.set_blocking(false);
+        let mut sent = 0;
+        while sent < req.contents.len() {
+            match remote.write(&req.contents[sent..]) {
+                Ok(written) => sent += written,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                Err(err) => return Err(err.into()),
+            }
+            if let Some(err) = interruption(started, exec_timeout, cancelled) {
+                return Err(err);
+            }
+        }
+        sess.set_blocking(true);
+        close_channel(&mut remote).map_err(|err| transfer_error(err, exec_timeout))?;
+
+        if let Some(owner) = &req.owner {
+            let chown = format!(
+                "chown {} {}",
+                shell_quote(owner),
+                shell_quote(&req.destination)
+            );
+            let channel = sess.channel_session()?;
+            let chowned = run_cmd(&sess, channel, &chown, exec_timeout, cancelled, None)?;
+            if chowned.exit_status != 0 {
+                return Err(Error::Transfer(format!(
+                    "chown to {} failed: {}",
+                    owner,
+                    chowned.stderr.unwrap_or_default().trim()
+                )));
+            }
+        }
+
+        sess.set_timeout(0);
+        self.pool.put(node_name, node, settings, sess);
+
+        Ok(size)
+    }
+
+    /// Fetches `source` from `node` over scp. The transfer is aborted once
+    /// `cancelled` returns true.
+    pub fn download(
+        &self,
+        node_name: &str,
+        node: &Node,
+        source: &str,
+        settings: &SshSettings,
+        cancelled: &dyn Fn() -> bool,
+    ) -> Result<Vec<u8>, Error> {
+        let exec_timeout = settings.timeouts.exec;
+        let sess = self.session(node_name, node, settings)?;
+        if let Some(seconds) = exec_timeout {
+            sess.set_timeout((seconds * 1000) as u32);
+        }
+
+        let started = Instant::now();
+        let (mut remote, stat) = sess
+            .scp_recv(Path::new(source))
+            .map_err(|err| transfer_error(err.into(), exec_timeout))?;
+        if stat.size() > MAX_TRANSFER_SIZE as u64 {
+            return Err(Error::Transfer(format!(
+                "{} is {} bytes, more than the {} bytes a download can carry",
+                source,
+                stat.size(),
+                MAX_TRANSFER_SIZE
+            )));
+        }
+
+        let mut contents = Vec::with_capacity(stat.size() as usize);
+        let mut chunk = [0; 16384];
+        sess.set_blocking(false);
+        loop {
+            match remote.read(&mut chunk) {
+                Ok(0) if remote.eof() => break,
+                Ok(0) => std::thread::sleep(Duration::from_millis(10)),
+                Ok(read) => contents.extend_from_slice(&chunk[..read]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                Err(err) => return Err(err.into()),
+            }
+            if let Some(err) = interruption(started, exec_timeout, cancelled) {
+                return Err(err);
+            }
+        }
+        sess.set_blocking(true);
+        close_channel(&mut remote).map_err(|err| transfer_error(err, exec_timeout))?;
+
+        sess.set_timeout(0);
+        self.pool.put(node_name, node, settings, sess);
+
+        Ok(contents)
+    }
+
+    /// Takes a pooled session for `node`, or opens a new one.
+    fn session(
+        &self,
+        node_name: &str,
+        node: &Node,
+        settings: &SshSettings,
+    ) -> Result<Session, Error> {
+        if let Some(sess) = self.pool.take(node_name, node, settings) {
+            return Ok(sess);
+        }
+
+        let sess = open_session(node, settings)?;
+        self.pool.opened(node_name);
+        Ok(sess)
+    }
+}
+
+/// Binds the daemon's socket, replacing the one a crashed daemon left behind,
+/// and sets its permissions. Socket settings aren't affected by a reload.
+fn bind(socket_path: &str, daemon_config: &DaemonConfig) -> io::Result<(UnixListener, (u64, u64))> {
+    if let Ok(metadata) = fs::symlink_metadata(socket_path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and isn't a socket", socket_path),
+            ));
+        }
+        match UnixStream::connect(socket_path) {
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("another daemon is listening on {}", socket_path),
+                ))
+            }
+            Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                warn!("Removing stale socket {}", socket_path);
+                fs::remove_file(socket_path)?;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    let listener = UnixListener::bind(socket_path)?;
+    let setup = || -> io::Result<(u64, u64)> {
+        listener.set_nonblocking(true)?;
+        if let Some(group) = &daemon_config.socket_group {
+            std::os::unix::fs::chown(socket_path, None, Some(group_id(group)?))?;
+        }
+        if let Some(mode) = daemon_config.socket_mode {
+            fs::set_permissions(socket_path, fs::Permissions::from_mode(mode))?;
+        }
+        let metadata = fs::metadata(socket_path)?;
+        Ok((metadata.dev(), metadata.ino()))
+    };
+
+    match setup() {
+        Ok(socket_id) => Ok((listener, socket_id)),
+        Err(err) => {
+            // Best effort, there's already an error to report.
+            let _ = fs::remove_file(socket_path);
+            Err(err)
+        }
+    }
+}
+
+fn group_id(group: &str) -> io::Result<u32> {
+    let unknown = || {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown group '{}'", group),
+        )
+    };
+    let name = CString::new(group).map_err(|_| unknown())?;
+    // Only called at startup, before any other thread could use getgrnam.
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(unknown());
+    }
+
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+/// Self-pipe the signal handlers write to, so that the accept loop wakes up
+/// as soon as a signal comes in.
+struct SignalPipe {
+    signals: Signals,
+    read: UnixStream,
+}
+
+impl SignalPipe {
+    fn new(sigs: &[c_int]) -> io::Result<SignalPipe> {
+        // Registered before the pipe, so `pending()` already knows about a
+        // signal once its byte is in there.
+        let signals = Signals::new(sigs)?;
+        let (read, write) = UnixStream::pair()?;
+        read.set_nonblocking(true)?;
+        for &sig in sigs {
+            signal_hook::pipe::register(sig, write.try_clone()?)?;
+        }
+
+        Ok(SignalPipe { signals, read })
+    }
+
+    /// Signals received since the last call.
+    fn take(&self) -> Vec<c_int> {
+        let mut buf = [0; 64];
+        while let Ok(read) = (&self.read).read(&mut buf) {
+            if read == 0 {
+                break;
+            }
+        }
+        self.signals.pending().collect()
+    }
+}
+
+impl AsRawFd for SignalPipe {
+    fn as_raw_fd(&self) -> RawFd {
+        self.read.as_raw_fd()
+    }
+}
+
+/// Waits for one of `fds` to be readable, at most `timeout` when one is
+/// given, and tells which ones are.
+fn poll_readable<const N: usize>(
+    fds: &[RawFd; N],
+    timeout: Option<Duration>,
+) -> io::Result<[bool; N]> {
+    let mut pollfds = fds.map(|fd| libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    });
+    let timeout = timeout.map_or(-1, |timeout| timeout.as_millis() as c_int);
+    loop {
+        let ready = unsafe { libc::poll(pollfds.as_mut_ptr(), N as libc::nfds_t, timeout) };
+        if ready >= 0 {
+            return Ok(pollfds.map(|pollfd| pollfd.revents != 0));
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Connects, through its jump hosts if it has some, checks the host key and
+/// authenticates on `node`, giving up on any stage that outlasts its timeout.
+fn open_session(node: &Node, settings: &SshSettings) -> Result<Session, Error> {
+    let jump_error = |name: Option<&str>, err: Error| match name {
+        Some(name) => Error::Jump(name.to_string(), Box::new(err)),
+        None => err,
+    };
+    let mut chain = settings
+        .jump_hosts
+        .iter()
+        .map(|&(name, jump_host, auth)| (Some(name), jump_host, auth))
+        .chain(std::iter::once((None, node, settings.auth)));
+    let (mut name, first, auth) = chain.next().unwrap();
+
+    let tcp = connect(first, settings.timeouts.connect).map_err(|err| jump_error(name, err))?;
+    let mut fd = tcp.as_raw_fd();
+    let mut sess = start_session(tcp, first, auth, settings);
+    for (next_name, next, next_auth) in chain {
+        let hop = sess.map_err(|err| jump_error(name, err))?;
+        if let Some(seconds) = settings.timeouts.handshake {
+            hop.set_timeout((seconds * 1000) as u32);
+        }
+
+        let (local, remote) = socket_pair()?;
+        let hop_fd = fd;
+        fd = remote.as_raw_fd();
+        // Channels can't move between threads, the relay opens its own.
+        let (opened_sender, opened) = bounded(1);
+        let (host, port) = (next.host.clone(), next.port);
+        std::thread::spawn(move || match hop.channel_direct_tcpip(&host, port, None) {
+            Ok(channel) => {
+                let _ = opened_sender.send(Ok(()));
+                hop.set_timeout(0);
+                relay(hop, channel, hop_fd, local);
+            }
+            Err(err) => {
+                let _ = opened_sender.send(Err(err));
+            }
+        });
+        if let Ok(Err(err)) = opened.recv() {
+            return Err(jump_error(name, err.into()));
+        }
+
+        sess = start_session(remote, next, next_auth, settings);
+        name = next_name;
+    }
+
+    sess.map_err(|err| jump_error(name, err))
+}
+
+/// Handshakes, checks the host key and authenticates on `node` over
+/// `stream`.
+fn start_session(
+    stream: TcpStream,
+    node: &Node,
+    auth: &Auth,
+    settings: &SshSettings,
+) -> Result<Session, Error> {
+    let timeouts = &settings.timeouts;
+    let mut sess = Session::new()?;
+    sess.set_tcp_stream(stream);
+    if let Some(seconds) = timeouts.handshake {
+        sess.set_timeout((seconds * 1000) as u32);
+    }
+    let handshake_timeout = |err: &ssh2::Error| match (err.code(), timeouts.handshake) {
+        (LIBSSH2_ERROR_TIMEOUT, Some(seconds)) => {
+            Some(Error::Timeout(TimeoutStage::Handshake, seconds))
+        }
+        _ => None,
+    };
+    if let Err(err) = sess.handshake() {
+        return Err(handshake_timeout(&err).unwrap_or_else(|| err.into()));
+    }
+    verify_host_key(&sess, node, settings.host_keys)?;
+    if let Err(err) = authenticate(&sess, &node.user, auth) {
+        return Err(match err {
+            Error::Ssh(ref ssh_err) => handshake_timeout(ssh_err),
+            _ => None,
+        }
+        .unwrap_or_else(|| Error::Auth(auth.clone(), Box::new(err))));
+    }
+    sess.set_timeout(0);
+
+    Ok(sess)
+}
+
+/// Two connected loopback sockets, libssh2 only taking TCP streams.
+fn socket_pair() -> io::Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let client = TcpStream::connect(listener.local_addr()?)?;
+    loop {
+        let (server, peer) = listener.accept()?;
+        // Some other local process may have connected first.
+        if peer == client.local_addr()? {
+            client.set_nodelay(true)?;
+            server.set_nodelay(true)?;
+            return Ok((client, server));
+        }
+    }
+}
+
+/// Relays the bytes of the session on the other end of `local` through the
+/// `channel` a jump host opened to its node, until either side closes.
+/// `hop_fd` is the socket of the jump host's session.
+fn relay(hop: Session, mut channel: Channel, hop_fd: RawFd, mut local: TcpStream) {
+    hop.set_blocking(false);
+    let fds = [hop_fd, local.as_raw_fd()];
+    let mut buf = [0; 16384];
+    // Read from the session but not taken by the channel yet.
+    let mut pending: Vec<u8> = Vec::new();
+    loop {
+        let readable = match poll_readable(&fds, Some(RELAY_POLL_INTERVAL)) {
+            Ok(readable) => readable,
+            Err(_) => return,
         };
+        if readable[1] {
+            match local.read(&mut buf) {
+                Ok(0) | Err(_) => return,
+                Ok(read) => pending.extend_from_slice(&buf[..read]),
+            }
+        }
 
-        let exit_status = channel.exit_status()?;
+        while !pending.is_empty() {
+            match channel.write(&pending) {
+                Ok(written) => {
+                    pending.drain(..written);
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => return,
+            }
+        }
 
-        Ok(SshSuccess {
-            stdout,
-            stderr,
-            exit_status,
-        })
+        loop {
+            match channel.read(&mut buf) {
+                Ok(0) if channel.eof() => return,
+                Ok(0) => break,
+                Ok(read) => {
+                    if local.write_all(&buf[..read]).is_err() {
+                        return;
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+/// Runs `cmd` on `channel` until it exits, its exec timeout runs out or
+/// `cancelled` returns true.
+fn run_cmd(
+    sess: &Session,
+    mut channel: Channel,
+    cmd: &str,
+    exec_timeout: Option<u64>,
+    cancelled: &dyn Fn() -> bool,
+    mut output: Option<&mut dyn FnMut(OutputKind, String)>,
+) -> Result<SshSuccess, Error> {
+    channel.exec(cmd)?;
+
+    // Both streams are polled without blocking, so that a command
+    // filling up stderr can't stall while we wait on stdout.
+    sess.set_blocking(false);
+    let started = Instant::now();
+    let mut stdout = OutputBuffer::new(OutputKind::Stdout);
+    let mut stderr = OutputBuffer::new(OutputKind::Stderr);
+    loop {
+        let mut read_any = stdout.fill(&mut channel.stream(0))?;
+        read_any |= stderr.fill(&mut channel.stderr())?;
+
+        if let Some(output) = output.as_mut() {
+            for buffer in [&mut stdout, &mut stderr] {
+                if let Some(lines) = buffer.take_lines() {
+                    output(buffer.kind, lines);
+                }
+            }
+        }
+
+        if !read_any && channel.eof() {
+            break;
+        }
+        // Checked after every read, a command that never stops writing
+        // would otherwise never time out nor be cancelled.
+        if let Some(err) = interruption(started, exec_timeout, cancelled) {
+            if let Some(output) = output.as_mut() {
+                for buffer in [&mut stdout, &mut stderr] {
+                    if let Some(rest) = buffer.take_all() {
+                        output(buffer.kind, rest);
+                    }
+                }
+            }
+            // Best effort, the session is dropped right after.
+            let _ = channel.close();
+            return Err(err);
+        }
+        if !read_any {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+    sess.set_blocking(true);
+    channel.wait_close()?;
+
+    let (stdout, stderr) = match output.as_mut() {
+        Some(output) => {
+            for buffer in [&mut stdout, &mut stderr] {
+                if let Some(rest) = buffer.take_all() {
+                    output(buffer.kind, rest);
+                }
+            }
+            (None, None)
+        }
+        None => (stdout.take_all(), stderr.take_all()),
+    };
+
+    let exit_status = channel.exit_status()?;
+
+    Ok(SshSuccess {
+        stdout,
+        stderr,
+        exit_status,
+    })
+}
+
+/// Why a command or transfer started at `started` must stop, if it must.
+fn interruption(
+    started: Instant,
+    exec_timeout: Option<u64>,
+    cancelled: &dyn Fn() -> bool,
+) -> Option<Error> {
+    match exec_timeout {
+        _ if cancelled() => Some(Error::Aborted),
+        Some(seconds) if started.elapsed() >= Duration::from_secs(seconds) => {
+            Some(Error::Timeout(TimeoutStage::Exec, seconds))
+        }
+        _ => None,
+    }
+}
+
+fn close_channel(channel: &mut Channel) -> Result<(), Error> {
+    channel.send_eof()?;
+    channel.wait_eof()?;
+    channel.close()?;
+    channel.wait_close()?;
+    Ok(())
+}
+
+/// Turns the errors of a blocking transfer that ran out of time into an exec
+/// timeout.
+fn transfer_error(err: Error, exec_timeout: Option<u64>) -> Error {
+    let timed_out = match &err {
+        Error::Ssh(ssh_err) => ssh_err.code() == LIBSSH2_ERROR_TIMEOUT,
+        Error::Io(io_err) => io_err.kind() == io::ErrorKind::TimedOut,
+        _ => false,
+    };
+
+    match exec_timeout {
+        Some(seconds) if timed_out => Error::Timeout(TimeoutStage::Exec, seconds),
+        _ => err,
+    }
+}
+
+/// `cmd` preceded by the environment and working directory of `node`.
+fn node_command(node: &Node, cmd: &str) -> String {
+    let mut command = String::new();
+    for (name, value) in &node.env {
+        command.push_str(&format!("export {}={}; ", name, shell_quote(value)));
+    }
+    if let Some(workdir) = &node.workdir {
+        command.push_str(&format!("cd {} || exit 1; ", shell_quote(workdir)));
+    }
+    command.push_str(cmd);
+
+    command
+}
+
+/// Quotes `arg` so that a POSIX shell reads it as a single word.
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+fn verify_host_key(sess: &Session, node: &Node, host_keys: &HostKeyConfig) -> Result<(), Error> {
+    if host_keys.policy == HostKeyPolicy::Off {
+        return Ok(());
+    }
+
+    // known_hosts only uses the `[host]:port` form for non standard ports.
+    let host = if node.port == 22 {
+        node.host.clone()
+    } else {
+        format!("[{}]:{}", node.host, node.port)
+    };
+    let (key, key_type) = sess.host_key().ok_or(HostKeyError::Unavailable)?;
+    let fingerprint = match sess.host_key_hash(HashType::Sha256) {
+        Some(hash) => format!(
+            "SHA256:{}",
+            base64::encode_config(hash, base64::STANDARD_NO_PAD)
+        ),
+        None => return Err(HostKeyError::Unavailable.into()),
+    };
+
+    let _guard = KNOWN_HOSTS_LOCK.lock().unwrap();
+    let mut known_hosts = sess.known_hosts()?;
+    if host_keys.known_hosts.exists() {
+        known_hosts.read_file(&host_keys.known_hosts, KnownHostFileKind::OpenSSH)?;
+    }
+
+    match known_hosts.check_port(&node.host, node.port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(HostKeyError::Mismatch { host, fingerprint }.into()),
+        CheckResult::NotFound if host_keys.policy == HostKeyPolicy::AcceptNew => {
+            // Appended rather than rewriting the whole file, which would lose
+            // its comments.
+            let mut new_host = sess.known_hosts()?;
+            new_host.add(&host, key, "added by oviumd", key_type.into())?;
+            let mut entry = String::new();
+            for known_host in new_host.iter() {
+                entry.push_str(&new_host.write_string(&known_host?, KnownHostFileKind::OpenSSH)?);
+            }
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&host_keys.known_hosts)?
+                .write_all(entry.as_bytes())?;
+            warn!(
+                "Added new host key {} for {} to known_hosts",
+                fingerprint, host
+            );
+            Ok(())
+        }
+        CheckResult::NotFound => Err(HostKeyError::Unknown { host, fingerprint }.into()),
+        CheckResult::Failure => Err(HostKeyError::Unavailable.into()),
+    }
+}
+
+fn authenticate(sess: &Session, user: &str, auth: &Auth) -> Result<(), Error> {
+    match auth {
+        Auth::Agent => sess.userauth_agent(user)?,
+        Auth::Key {
+            key_file,
+            passphrase_file,
+        } => {
+            let passphrase = match passphrase_file {
+                Some(passphrase_file) => Some(read_secret(passphrase_file)?),
+                None => None,
+            };
+            sess.userauth_pubkey_file(user, None, key_file, passphrase.as_deref())?
+        }
+        Auth::Password { password_file } => {
+            sess.userauth_password(user, &read_secret(password_file)?)?
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a secret from a file, without the trailing newline most editors add.
+fn read_secret(file: &Path) -> Result<String, Error> {
+    let secret = read_file(file)?;
+    Ok(secret.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+/// Connects to the first address `node` resolves to that accepts the
+/// connection within `timeout` seconds.
+fn connect(node: &Node, timeout: Option<u64>) -> Result<TcpStream, Error> {
+    let node_addr = (node.host.as_str(), node.port);
+    let seconds = match timeout {
+        Some(seconds) => seconds,
+        None => return Ok(TcpStream::connect(node_addr)?),
+    };
+
+    // Every address is tried, a timeout is only reported once none of them
+    // answered.
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no address to connect to");
+    let mut timed_out = false;
+    for addr in node_addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, Duration::from_secs(seconds)) {
+            Ok(tcp) => return Ok(tcp),
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => timed_out = true,
+            Err(err) => last_err = err,
+        }
+    }
+
+    if timed_out {
+        return Err(Error::Timeout(TimeoutStage::Connect, seconds));
+    }
+    Err(last_err.into())
+}
+
+struct OutputBuffer {
+    kind: OutputKind,
+    buf: Vec<u8>,
+}
+
+impl OutputBuffer {
+    fn new(kind: OutputKind) -> OutputBuffer {
+        OutputBuffer {
+            kind,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Does a single non-blocking read on `stream`, returns whether anything
+    /// was read.
+    fn fill<R: Read>(&mut self, stream: &mut R) -> Result<bool, Error> {
+        let mut chunk = [0; 4096];
+        match stream.read(&mut chunk) {
+            Ok(read_bytes) => {
+                self.buf.extend_from_slice(&chunk[..read_bytes]);
+                Ok(read_bytes > 0)
+            }
+            Err(err) => match err.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => Ok(false),
+                _ => Err(err.into()),
+            },
+        }
+    }
+
+    /// Takes every complete line out of the buffer, leaving a trailing
+    /// partial line for later.
+    fn take_lines(&mut self) -> Option<String> {
+        let end = self.buf.iter().rposition(|&b| b == b'\n')? + 1;
+        let lines: Vec<u8> = self.buf.drain(..end).collect();
+        Some(String::from_utf8_lossy(&lines).into_owned())
+    }
+
+    fn take_all(&mut self) -> Option<String> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        let rest = std::mem::take(&mut self.buf);
+        Some(String::from_utf8_lossy(&rest).into_owned())
     }
 }
 
 impl Drop for Server<'_> {
     fn drop(&mut self) {
-        std::fs::remove_file(&self.socket_path).unwrap();
+        let ours = match fs::symlink_metadata(self.socket_path) {
+            Ok(metadata) => (metadata.dev(), metadata.ino()) == self.socket_id,
+            Err(_) => false,
+        };
+        if !ours {
+            warn!("{} isn't our socket anymore, leaving it", self.socket_path);
+            return;
+        }
+        if let Err(err) = fs::remove_file(self.socket_path) {
+            warn!("Failed to remove socket {}: {}", self.socket_path, err);
+        }
     }
 }
 
 impl ServerConfig {
     pub fn new(config_dir: &Path) -> Result<ServerConfig, OviumError> {
-        let nodes_file_path = config_dir.join("nodes.toml");
-        let nodes_config_string = match read_file(&nodes_file_path) {
-            Ok(config_string) => config_string,
+        let files = match config_files(config_dir) {
+            Ok(files) if files.is_empty() => {
+                let reason = format!("no config file in {:?}", config_dir);
+                error!("{}", reason);
+                let err = io::Error::new(io::ErrorKind::NotFound, reason);
+                return Err(OviumError::from((ErrorKind::LoadConfig, err.into())));
+            }
+            Ok(files) => files,
             Err(err) => {
-                error!("Unable to load file {:?}: {}", &nodes_file_path, err);
-                return Err(OviumError::from((ErrorKind::LoadConfig, err)));
+                error!(
+                    "Unable to list the config files of {:?}: {}",
+                    config_dir, err
+                );
+                return Err(OviumError::from((ErrorKind::LoadConfig, err.into())));
             }
         };
 
-        let config: ServerConfig = toml::from_str(&nodes_config_string)
-            .map_err(|err| (ErrorKind::InvalidConfig, ConfigError::Parse(err).into()))?;
-        validate_config(&config).map_err(|err| (ErrorKind::InvalidConfig, err.into()))?;
+        let mut config = ServerConfig::default();
+        for file in &files {
+            let config_string = match read_file(file) {
+                Ok(config_string) => config_string,
+                Err(err) => {
+                    error!("Unable to load file {:?}: {}", file, err);
+                    return Err(OviumError::from((ErrorKind::LoadConfig, err)));
+                }
+            };
+            let config_file: ConfigFile = toml::from_str(&config_string).map_err(|err| {
+                (
+                    ErrorKind::InvalidConfig,
+                    ConfigError::Parse(file.clone(), err).into(),
+                )
+            })?;
+            config
+                .merge(config_file, file)
+                .map_err(|err| (ErrorKind::InvalidConfig, err.into()))?;
+        }
+
+        if let Some(path) = &config.audit.path {
+            config.audit.path = Some(config_dir.join(path));
+        }
+        config.log.resolve_paths(config_dir);
+        if let Some(path) = &config.history.path {
+            config.history.path = Some(config_dir.join(path));
+        }
+        for inventory in &mut config.inventory {
+            inventory.resolve_paths(config_dir);
+        }
+
+        Ok(config)
+    }
+
+    /// Adds what `config_file` at `path` defines, failing if another file
+    /// already defined some of it.
+    fn merge(&mut self, config_file: ConfigFile, path: &Path) -> Result<(), ConfigError> {
+        let origin = format!("{:?}", path);
+        for (name, node) in config_file.nodes {
+            self.claim(format!("Node '{}'", name), &origin)?;
+            self.nodes.insert(name, node);
+        }
+        for (name, members) in config_file.groups {
+            self.claim(format!("Group '{}'", name), &origin)?;
+            self.groups.insert(name, members);
+        }
+        self.acl.extend(config_file.acl);
+        self.inventory.extend(config_file.inventory);
+
+        macro_rules! merge_section {
+            ($($section:ident),*) => {$(
+                if let Some(section) = config_file.$section {
+                    self.claim(format!("Section [{}]", stringify!($section)), &origin)?;
+                    self.$section = section;
+                }
+            )*};
+        }
+        merge_section!(defaults, host_keys, pool, server, audit, log, history);
+
+        Ok(())
+    }
+
+    /// Records that `what` is defined by `origin`, failing if something else
+    /// already defined it.
+    fn claim(&mut self, what: String, origin: &str) -> Result<(), ConfigError> {
+        match self.origins.insert(what.clone(), origin.to_string()) {
+            Some(first) => Err(ConfigError::Duplicate {
+                what,
+                first,
+                second: origin.to_string(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// This config along with the nodes and groups read by `inventory`,
+    /// validated.
+    pub fn with_inventory(&self, inventory: &Inventory) -> Result<ServerConfig, ConfigError> {
+        let mut config = self.clone();
+        inventory.for_each(|data, origin| {
+            for (name, node) in &data.nodes {
+                config.claim(format!("Node '{}'", name), &origin)?;
+                config.nodes.insert(name.clone(), node.clone());
+            }
+            for (name, members) in &data.groups {
+                config.claim(format!("Group '{}'", name), &origin)?;
+                config.groups.insert(name.clone(), members.clone());
+            }
+            Ok(())
+        })?;
+        validate_config(&config)?;
 
         Ok(config)
     }
 }
 
+/// Reads all the sources of `inventory`, set up for `base_config`, and
+/// returns the config with their nodes.
+fn load_inventory(
+    base_config: &ServerConfig,
+    inventory: &Inventory,
+) -> Result<ServerConfig, OviumError> {
+    inventory
+        .refresh(true)
+        .map_err(|err| (ErrorKind::LoadConfig, err))?;
+
+    Ok(base_config
+        .with_inventory(inventory)
+        .map_err(|err| (ErrorKind::InvalidConfig, err.into()))?)
+}
+
+/// Files of `config_dir` the config is read from, in the order they're
+/// merged: `oviumd.toml` for the daemon settings, `nodes.toml`, the
+/// `nodes.d/*.toml` fragments by name, then `groups.toml`. Each is optional.
+pub fn config_files(config_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![
+        config_dir.join("oviumd.toml"),
+        config_dir.join("nodes.toml"),
+    ];
+
+    let fragments_dir = config_dir.join("nodes.d");
+    if fragments_dir.is_dir() {
+        let mut fragments = Vec::new();
+        for entry in fs::read_dir(&fragments_dir)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "toml")
+            {
+                fragments.push(path);
+            }
+        }
+        fragments.sort();
+        files.extend(fragments);
+    }
+
+    files.push(config_dir.join("groups.toml"));
+    files.retain(|file| file.is_file());
+
+    Ok(files)
+}
+
 fn validate_config(config: &ServerConfig) -> Result<(), ConfigError> {
+    let mut names: Vec<&String> = config.nodes.keys().collect();
+    names.sort();
+    for name in names {
+        validate_node(config, name, &config.nodes[name])?;
+    }
+
     let mut unknown_nodes: Vec<String> = Vec::new();
     for node_group in &config.groups {
         for node in node_group.1 {
-            if !config.nodes.contains_key(node) {
+            if !config.nodes.contains_key(node) && !config.is_group(node) {
                 unknown_nodes.push(node.to_string());
             }
         }
     }
 
+    if let Some(cycle) = find_group_cycle(config) {
+        return Err(ConfigError::GroupCycle(cycle));
+    }
+
+    for rule in &config.acl {
+        match config.select_nodes(&rule.nodes) {
+            Ok(_) => (),
+            Err(SelectorError::Unknown(terms)) => unknown_nodes.extend(terms),
+            Err(SelectorError::Syntax(reason)) => return Err(ConfigError::InvalidSelector(reason)),
+        }
+    }
+
     if !unknown_nodes.is_empty() {
         return Err(ConfigError::UnknownNodes(unknown_nodes));
     }
@@ -214,6 +1687,95 @@ fn validate_config(config: &ServerConfig) -> Result<(), ConfigError> {
     Ok(())
 }
 
+fn validate_node(config: &ServerConfig, name: &str, node: &Node) -> Result<(), ConfigError> {
+    let invalid = |reason: String| ConfigError::InvalidNode {
+        node: name.to_string(),
+        reason,
+    };
+
+    // Node names end up in the paths downloads are saved to.
+    if name.contains('/') || name.contains("..") {
+        return Err(invalid("names can't contain '/' or '..'".to_string()));
+    }
+    if node.host.is_empty() {
+        return Err(invalid("no host".to_string()));
+    }
+    if node.host.starts_with('[') {
+        return Err(invalid(format!(
+            "write the IPv6 address {} without brackets",
+            node.host
+        )));
+    }
+    if node.port == 0 {
+        return Err(invalid("port 0".to_string()));
+    }
+    for jump_host in &node.proxy_jump {
+        if jump_host == name {
+            return Err(invalid("jumps through itself".to_string()));
+        }
+        if !config.nodes.contains_key(jump_host) {
+            return Err(invalid(format!("unknown jump host '{}'", jump_host)));
+        }
+    }
+    // They're set by the shell running the command.
+    let is_variable_name = |name: &str| {
+        name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    };
+    if let Some(variable) = node.env.keys().find(|variable| !is_variable_name(variable)) {
+        return Err(invalid(format!(
+            "'{}' isn't a valid environment variable name",
+            variable
+        )));
+    }
+
+    Ok(())
+}
+
+/// Looks for a group containing itself, directly or not, and returns the
+/// groups leading from it back to it.
+fn find_group_cycle(config: &ServerConfig) -> Option<Vec<String>> {
+    fn visit<'a>(
+        config: &'a ServerConfig,
+        name: &'a String,
+        path: &mut Vec<&'a String>,
+        checked: &mut HashSet<&'a String>,
+    ) -> Option<Vec<String>> {
+        if let Some(start) = path.iter().position(|group| *group == name) {
+            let mut cycle: Vec<String> = path[start..]
+                .iter()
+                .map(|group| group.to_string())
+                .collect();
+            cycle.push(name.to_string());
+            return Some(cycle);
+        }
+        if checked.contains(name) {
+            return None;
+        }
+
+        path.push(name);
+        for member in &config.groups[name] {
+            // A node shadows a group of the same name.
+            if config.is_group(member) && !config.nodes.contains_key(member) {
+                if let Some(cycle) = visit(config, member, path, checked) {
+                    return Some(cycle);
+                }
+            }
+        }
+        path.pop();
+        checked.insert(name);
+
+        None
+    }
+
+    let mut names: Vec<&String> = config.groups.keys().collect();
+    names.sort();
+    let mut checked = HashSet::new();
+    names
+        .into_iter()
+        .find_map(|name| visit(config, name, &mut Vec::new(), &mut checked))
+}
+
 fn read_file(file: &Path) -> Result<String, Error> {
     let mut f = File::open(file)?;
     let mut file_string = String::new();
@@ -221,3 +1783,239 @@ fn read_file(file: &Path) -> Result<String, Error> {
 
     Ok(file_string)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node() -> Node {
+        toml::from_str("host = \"10.0.0.1\"").unwrap()
+    }
+
+    fn config(nodes: &[&str], groups: &[(&str, &[&str])]) -> ServerConfig {
+        let mut config = ServerConfig::default();
+        for name in nodes {
+            config.nodes.insert(name.to_string(), node());
+        }
+        for (name, members) in groups {
+            let members = members.iter().map(|member| member.to_string()).collect();
+            config.groups.insert(name.to_string(), members);
+        }
+
+        config
+    }
+
+    #[test]
+    fn group_containing_itself_is_a_cycle() {
+        let config = config(&["web1"], &[("web", &["web1", "web"])]);
+        assert_eq!(find_group_cycle(&config).unwrap(), ["web", "web"]);
+        assert_eq!(
+            config.group_nodes("web"),
+            BTreeSet::from([&"web1".to_string()])
+        );
+    }
+
+    #[test]
+    fn indirect_cycle_is_reported_along_its_path() {
+        let config = config(
+            &["web1", "db1"],
+            &[
+                ("all", &["front"]),
+                ("front", &["web1", "back"]),
+                ("back", &["db1", "dc"]),
+                ("dc", &["front"]),
+            ],
+        );
+        assert_eq!(
+            find_group_cycle(&config).unwrap(),
+            ["front", "back", "dc", "front"]
+        );
+        assert_eq!(
+            config.group_nodes("all").into_iter().collect::<Vec<_>>(),
+            ["db1", "web1"]
+        );
+    }
+
+    #[test]
+    fn nested_groups_without_cycle_expand() {
+        let config = config(
+            &["web1", "web2", "db1"],
+            &[
+                ("all", &["front", "back", "web1"]),
+                ("front", &["web1", "web2"]),
+                ("back", &["db1"]),
+            ],
+        );
+        assert!(find_group_cycle(&config).is_none());
+        assert_eq!(
+            config.group_nodes("all").into_iter().collect::<Vec<_>>(),
+            ["db1", "web1", "web2"]
+        );
+        assert!(config.group_nodes("web1").is_empty());
+    }
+
+    #[test]
+    fn node_shadows_group_of_the_same_name() {
+        let config = config(
+            &["web1", "db"],
+            &[("web", &["web1", "db"]), ("db", &["web"])],
+        );
+        assert!(find_group_cycle(&config).is_none());
+        assert_eq!(
+            config.group_nodes("web").into_iter().collect::<Vec<_>>(),
+            ["db", "web1"]
+        );
+    }
+
+    /// A fresh config directory holding `files`, relative paths to contents.
+    fn config_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ovium-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for (path, content) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+
+        dir
+    }
+
+    #[test]
+    fn config_files_come_in_a_fixed_order() {
+        let dir = config_dir(
+            "config-files",
+            &[
+                ("groups.toml", ""),
+                ("nodes.d/b-par2.toml", ""),
+                ("nodes.d/a-par1.toml", ""),
+                ("nodes.d/notes.txt", ""),
+                ("nodes.toml", ""),
+                ("oviumd.toml", ""),
+            ],
+        );
+        let files = config_files(&dir).unwrap();
+        let names: Vec<_> = files
+            .iter()
+            .map(|file| file.strip_prefix(&dir).unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "oviumd.toml",
+                "nodes.toml",
+                "nodes.d/a-par1.toml",
+                "nodes.d/b-par2.toml",
+                "groups.toml"
+            ]
+        );
+
+        fs::remove_file(dir.join("nodes.toml")).unwrap();
+        fs::remove_dir_all(dir.join("nodes.d")).unwrap();
+        assert_eq!(
+            config_files(&dir).unwrap(),
+            [dir.join("oviumd.toml"), dir.join("groups.toml")]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fragments_add_up() {
+        let dir = config_dir(
+            "config-merge",
+            &[
+                (
+                    "nodes.d/par1.toml",
+                    "[nodes]\nweb1 = { host = \"10.0.0.1\" }\n",
+                ),
+                (
+                    "nodes.d/par2.toml",
+                    "[nodes]\nweb2 = { host = \"10.0.0.2\" }\n",
+                ),
+                ("groups.toml", "[groups]\nweb = [\"web1\", \"web2\"]\n"),
+            ],
+        );
+        let config = ServerConfig::new(&dir).unwrap();
+        assert_eq!(config.nodes.len(), 2);
+        assert_eq!(config.group_nodes("web").len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn duplicate_node_names_both_files() {
+        let node = "[nodes]\nweb1 = { host = \"10.0.0.1\" }\n";
+        let dir = config_dir(
+            "config-duplicate-node",
+            &[("nodes.d/par2.toml", node), ("nodes.d/par1.toml", node)],
+        );
+        let err = ServerConfig::new(&dir).unwrap_err().to_string();
+        let expected = format!(
+            "Node 'web1' is defined in both {:?} and {:?}",
+            dir.join("nodes.d/par1.toml"),
+            dir.join("nodes.d/par2.toml")
+        );
+        assert!(err.contains(&expected), "{}", err);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn duplicate_section_names_both_files() {
+        let mut config = ServerConfig::default();
+        let file = || toml::from_str::<ConfigFile>("[pool]\nidle_timeout = 30\n").unwrap();
+        config.merge(file(), Path::new("oviumd.toml")).unwrap();
+        match config.merge(file(), Path::new("nodes.d/par1.toml")) {
+            Err(ConfigError::Duplicate {
+                what,
+                first,
+                second,
+            }) => {
+                assert_eq!(what, "Section [pool]");
+                assert_eq!(first, "\"oviumd.toml\"");
+                assert_eq!(second, "\"nodes.d/par1.toml\"");
+            }
+            other => panic!("unexpected merge {:?}", other.map(|_| ())),
+        }
+    }
+
+    /// Runs the server side of the handshake against `client_hello` and
+    /// returns its outcome along with the answer the client got.
+    fn handshake(client_hello: Hello) -> (Result<(), Error>, Response) {
+        let (server_side, client_side) = UnixStream::pair().unwrap();
+        let handshake = Server::handshake(&server_side, &client_hello);
+        let response = Response::read_from(&mut BufReader::new(&client_side))
+            .unwrap()
+            .unwrap();
+
+        (handshake, response)
+    }
+
+    #[test]
+    fn current_client_passes_the_handshake() {
+        match handshake(Hello::default()) {
+            (Ok(()), Response::Hello(hello)) => {
+                assert_eq!(hello.protocol_version, PROTOCOL_VERSION)
+            }
+            other => panic!("unexpected handshake {:?}", other),
+        }
+    }
+
+    #[test]
+    fn old_client_is_told_the_protocol_is_incompatible() {
+        let old = PROTOCOL_VERSION - 1;
+        let client_hello = Hello {
+            protocol_version: old,
+            min_protocol_version: 1,
+            ..Hello::default()
+        };
+        match handshake(client_hello) {
+            (
+                Err(Error::RequestError(RequestError::IncompatibleProtocol(version))),
+                Response::Error(ResponseError::IncompatibleProtocol { client, server }),
+            ) => {
+                assert_eq!(version, old);
+                assert_eq!(client, (1, old));
+                assert_eq!(server, (MIN_PROTOCOL_VERSION, PROTOCOL_VERSION));
+            }
+            other => panic!("unexpected handshake {:?}", other),
+        }
+    }
+}
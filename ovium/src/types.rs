@@ -1,86 +1,532 @@
-use crate::error::Error;
-use crate::server::ServerConfig;
-use serde::{Deserialize, Deserializer, Serialize};
+use crate::acl::Peer;
+use crate::audit::AuditTrail;
+use crate::error::{Error, FrameError};
+use crate::server::{Server, ServerConfig};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fmt::{self, Display};
+use std::io::{self, Read};
 use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, UNIX_EPOCH};
 
 const RED: &str = "\x1b[0;31m";
 const GREEN: &str = "\x1b[0;32m";
 const NC: &str = "\x1b[0m";
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Whether `Display` implementations use ANSI colours, see `set_colors`.
+static COLORS: AtomicBool = AtomicBool::new(true);
+
+/// First byte of every frame, lets a peer speaking something else be told
+/// apart from a corrupted payload.
+pub const FRAME_MAGIC: u8 = 0x0f;
+/// Version of the frame layout itself (not of the messages it carries).
+pub const FRAME_VERSION: u8 = 1;
+/// Frame header: magic, version, then the payload length as a big endian u32.
+pub const FRAME_HEADER_SIZE: usize = 6;
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+/// Largest file an upload or a download carries, leaving room for the rest
+/// of the message in its frame.
+pub const MAX_TRANSFER_SIZE: usize = MAX_FRAME_SIZE / 2;
+
+/// Version of the `Request`/`Response` messages, bumped whenever they change
+/// in a way an older peer couldn't decode:
+///
+/// - 2: timeouts in `CmdRequest` and `SshReturn::SshTimeout`
+/// - 3: `CmdRequest::parallelism` and `batch`, `SshReturn::SshSkipped`
+/// - 4: transfer requests and `ResponseError::InvalidRequest`
+/// - 5: `CmdReturn::duration`
+/// - 6: `SshReturn::SshAborted`
+/// - 7: `ResponseError::Forbidden`
+/// - 8: jobs
+/// - 9: the history and reruns
+pub const PROTOCOL_VERSION: u32 = 9;
+/// Oldest protocol version this build can still speak.
+pub const MIN_PROTOCOL_VERSION: u32 = 9;
+/// Optional features this build supports, advertised during the handshake.
+pub const CAPABILITIES: &[&str] = &[
+    "cmd", "stream", "status", "transfer", "reload", "jobs", "history",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CmdReturn {
     pub node_name: String,
     pub data: SshReturn,
+    /// Time spent on the node, connection included.
+    pub duration: Duration,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CmdRequest {
     pub nodes: Vec<String>,
     pub command: String,
+    pub stream: bool,
+    pub timeouts: Timeouts,
+    pub parallelism: Option<Parallelism>,
+    pub batch: Option<BatchPolicy>,
+}
+
+/// Maximum number of nodes a command runs on at the same time.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Parallelism {
+    Count(usize),
+    Percent(u8),
+}
+
+/// Runs the nodes in successive batches of `parallelism` nodes, which must be
+/// set, and stops the rollout once a batch has more than `max_failure_ratio`
+/// (0.0 to 1.0) of its nodes failing.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct BatchPolicy {
+    pub max_failure_ratio: f32,
 }
 
+/// Timeouts in seconds. Unset ones fall back, in order, on the request, the
+/// node, the `[defaults]` config section and finally `Timeouts::BUILTIN`.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+    pub connect: Option<u64>,
+    pub handshake: Option<u64>,
+    pub exec: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum TimeoutStage {
+    Connect,
+    Handshake,
+    Exec,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum OutputKind {
+    Stdout,
+    Stderr,
+}
+
+/// A chunk of complete output lines produced by a node, sent as soon as it
+/// is read when the request asked for streaming.
 #[derive(Serialize, Deserialize, Debug)]
+pub struct CmdOutput {
+    pub node_name: String,
+    pub kind: OutputKind,
+    pub data: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum SshReturn {
-    #[serde(deserialize_with = "unescape_new_line")]
     SshSuccess(SshSuccess),
     SshFailure(String),
+    SshTimeout(TimeoutStage, u64),
+    /// Not run because a previous batch failed, with that batch failure ratio.
+    SshSkipped(f32),
+    /// Cancelled, or never started, because the server shut down or the
+    /// job was cancelled.
+    SshAborted,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SshSuccess {
     pub stdout: Option<String>,
     pub stderr: Option<String>,
     pub exit_status: i32,
 }
 
+/// First message sent by both sides on a new connection. The client sends the
+/// range of versions it speaks, the server answers with the version picked
+/// for the rest of the connection.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub min_protocol_version: u32,
+    pub capabilities: Vec<String>,
+}
+
+/// Copies `contents` to `destination` on every node.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadRequest {
+    pub nodes: Vec<String>,
+    pub destination: String,
+    pub contents: Vec<u8>,
+    pub mode: i32,
+    /// `user` or `user:group`, as understood by chown.
+    pub owner: Option<String>,
+    pub timeouts: Timeouts,
+}
+
+/// Fetches `source` from every node. `destination` is the local path to
+/// write each copy to, where `{node}` is replaced by the node name.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DownloadRequest {
+    pub nodes: Vec<String>,
+    pub source: String,
+    pub destination: String,
+    pub timeouts: Timeouts,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TransferReturn {
+    pub node_name: String,
+    pub data: TransferResult,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum TransferResult {
+    Uploaded(u64),
+    /// `path` is where the client saved `contents`, which it works out from
+    /// its own request.
+    Downloaded {
+        path: String,
+        contents: Vec<u8>,
+    },
+    SshFailure(String),
+    SshTimeout(TimeoutStage, u64),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StatusRequest {}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServerStatus {
+    pub pool: Vec<PoolStatus>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReloadRequest {}
+
+/// Summary of a configuration swapped in by a reload.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReloadReport {
+    pub nodes: usize,
+    pub groups: usize,
+}
+
+/// Runs `cmd` in the background on the server, which answers with the job
+/// right away and keeps its results for `JobRequest`s.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SubmitRequest {
+    pub cmd: CmdRequest,
+}
+
+/// Queries or cancels a submitted job, by id.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum JobRequest {
+    Status(u64),
+    /// Answers with the results once the job is over.
+    Wait(u64),
+    /// Answers with the results of the nodes done so far.
+    Output(u64),
+    List,
+    Cancel(u64),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum JobState {
+    Running,
+    Finished,
+    /// Cancel was asked for, nodes still running are being aborted.
+    Cancelled,
+}
+
+/// A submitted job, as reported to clients.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JobInfo {
+    /// Id of the request that submitted the job.
+    pub id: u64,
+    pub command: String,
+    pub nodes: Vec<String>,
+    pub user: Option<String>,
+    pub state: JobState,
+    /// Number of nodes done, and how many of them didn't succeed.
+    pub done: usize,
+    pub failed: usize,
+    /// Seconds since the epoch.
+    pub submitted_at: f64,
+    pub finished_at: Option<f64>,
+}
+
+/// Looks up the command requests kept in the server history.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum HistoryRequest {
+    /// Answers with the matching entries, newest first and without their
+    /// output.
+    List(HistoryFilter),
+    Show(u64),
+}
+
+/// Which history entries to list, every criterion must match.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct HistoryFilter {
+    /// Nodes or groups, matching the entries that ran on any of them.
+    pub nodes: Vec<String>,
+    /// Only the entries where some node didn't succeed.
+    pub failed: bool,
+    /// Only the entries where some node exited with this status.
+    pub exit_code: Option<i32>,
+    /// Seconds since the epoch.
+    pub since: Option<f64>,
+    pub until: Option<f64>,
+    pub limit: Option<usize>,
+}
+
+/// Runs the command of history entry `id` again, on the nodes where it
+/// didn't succeed.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RerunRequest {
+    pub id: u64,
+    pub stream: bool,
+    pub timeouts: Timeouts,
+    pub parallelism: Option<Parallelism>,
+}
+
+/// A command request and what it returned on each node, as kept in the
+/// history.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryEntry {
+    /// Id of the request, the job id for submitted ones.
+    pub id: u64,
+    pub command: String,
+    /// Nodes and groups as requested.
+    pub targets: Vec<String>,
+    pub nodes: Vec<String>,
+    pub uid: u32,
+    pub user: Option<String>,
+    /// Whether it was submitted as a job.
+    pub job: bool,
+    /// Seconds since the epoch.
+    pub started_at: f64,
+    pub ended_at: f64,
+    pub results: Vec<CmdReturn>,
+}
+
+/// Session pool counters of a single node.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PoolStatus {
+    pub node_name: String,
+    pub idle_sessions: usize,
+    pub opened: u64,
+    pub reused: u64,
+    pub discarded: u64,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Response {
     Cmd(Vec<CmdReturn>),
     Error(ResponseError),
+    CmdOutput(CmdOutput),
+    CmdStatus(CmdReturn),
+    Hello(Hello),
+    Status(ServerStatus),
+    Transfer(TransferReturn),
+    Reload(ReloadReport),
+    Job(JobInfo),
+    Jobs(Vec<JobInfo>),
+    History(Vec<HistoryEntry>),
+    HistoryEntry(HistoryEntry),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Request {
     Cmd(CmdRequest),
+    Hello(Hello),
+    Status(StatusRequest),
+    Upload(UploadRequest),
+    Download(DownloadRequest),
+    Reload(ReloadRequest),
+    Submit(SubmitRequest),
+    Job(JobRequest),
+    History(HistoryRequest),
+    Rerun(RerunRequest),
 }
 
+/// Bincode encodes variants by their index, new ones go at the end.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ResponseError {
     UnknownNodes(Vec<String>),
+    /// Supported `(min, max)` protocol versions of each side.
+    IncompatibleProtocol {
+        client: (u32, u32),
+        server: (u32, u32),
+    },
+    InvalidRequest(String),
+    /// The reloaded configuration is invalid, the server kept the current one.
+    InvalidConfig(String),
+    /// The ACL doesn't allow the client to make this request.
+    Forbidden(String),
+    /// No such job, or it was forgotten.
+    UnknownJob(u64),
+    /// No such history entry, or it was dropped.
+    UnknownHistoryEntry(u64),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Node {
-    pub ip: String,
+    /// Host name or IP address, IPv6 ones without brackets.
+    #[serde(alias = "ip")]
+    pub host: String,
     #[serde(default = "default_port")]
-    pub port: u32,
+    pub port: u16,
     #[serde(default = "default_user")]
     pub user: String,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    pub auth: Option<Auth>,
+    /// Free-form `key = value` pairs nodes can be selected by.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Nodes to hop through to reach this one, the first one being connected
+    /// to directly, like ssh's ProxyJump.
+    #[serde(default)]
+    pub proxy_jump: Vec<String>,
+    /// Environment variables commands run with.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Directory commands run in, the user's home if unset.
+    pub workdir: Option<String>,
 }
 
-fn default_user() -> String {
-    "root".to_string()
+/// How oviumd authenticates on a node, `Agent` when neither the node nor the
+/// `[defaults]` config section set one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "method", rename_all = "lowercase")]
+pub enum Auth {
+    Agent,
+    Key {
+        key_file: PathBuf,
+        passphrase_file: Option<PathBuf>,
+    },
+    Password {
+        password_file: PathBuf,
+    },
 }
 
-fn default_port() -> u32 {
-    22
+impl Default for Hello {
+    fn default() -> Self {
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+}
+
+impl Hello {
+    /// Highest protocol version both sides speak, if any.
+    pub fn negotiate(&self, peer: &Hello) -> Option<u32> {
+        let version = self.protocol_version.min(peer.protocol_version);
+        if version < self.min_protocol_version || version < peer.min_protocol_version {
+            return None;
+        }
+
+        Some(version)
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+impl Parallelism {
+    /// Number of nodes allowed to run at once out of `nodes_nb`, never less
+    /// than one.
+    pub fn limit(&self, nodes_nb: usize) -> usize {
+        let limit = match self {
+            Parallelism::Count(count) => *count,
+            Parallelism::Percent(percent) => (nodes_nb * *percent as usize).div_ceil(100),
+        };
+        limit.clamp(1, nodes_nb.max(1))
+    }
+}
+
+impl SshReturn {
+    pub fn is_success(&self) -> bool {
+        matches!(self, SshReturn::SshSuccess(success) if success.exit_status == 0)
+    }
+}
+
+impl Timeouts {
+    /// No exec timeout by default, a command may legitimately run for hours.
+    pub const BUILTIN: Timeouts = Timeouts {
+        connect: Some(10),
+        handshake: Some(10),
+        exec: None,
+    };
+
+    /// Fills the timeouts left unset in `self` from `fallback`.
+    pub fn or(self, fallback: Timeouts) -> Timeouts {
+        Timeouts {
+            connect: self.connect.or(fallback.connect),
+            handshake: self.handshake.or(fallback.handshake),
+            exec: self.exec.or(fallback.exec),
+        }
+    }
+}
+
+impl JobRequest {
+    pub fn id(&self) -> Option<u64> {
+        match self {
+            JobRequest::Status(id)
+            | JobRequest::Wait(id)
+            | JobRequest::Output(id)
+            | JobRequest::Cancel(id) => Some(*id),
+            JobRequest::List => None,
+        }
+    }
 }
 
-fn unescape_new_line<'de, D>(deserializer: D) -> Result<SshSuccess, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let mut ssh_success: SshSuccess = Deserialize::deserialize(deserializer)?;
-    if let Some(stdout) = ssh_success.stdout {
-        ssh_success.stdout = Some(stdout.replace("\\n", "\n"));
+impl Request {
+    /// Short name of the request, for the logs.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Request::Cmd(_) => "cmd",
+            Request::Hello(_) => "hello",
+            Request::Status(_) => "status",
+            Request::Upload(_) => "upload",
+            Request::Download(_) => "download",
+            Request::Reload(_) => "reload",
+            Request::Submit(_) => "submit",
+            Request::Job(_) => "job",
+            Request::History(_) => "history",
+            Request::Rerun(_) => "rerun",
+        }
     }
 
-    if let Some(stderr) = ssh_success.stderr {
-        ssh_success.stderr = Some(stderr.replace("\\n", "\n"));
+    /// Capabilities the server must have advertised to handle this request.
+    pub fn required_capabilities(&self) -> Vec<&'static str> {
+        match self {
+            Request::Cmd(cmd_request) if cmd_request.stream => vec!["cmd", "stream"],
+            Request::Cmd(_) => vec!["cmd"],
+            Request::Hello(_) => vec![],
+            Request::Status(_) => vec!["status"],
+            Request::Upload(_) | Request::Download(_) => vec!["transfer"],
+            Request::Reload(_) => vec!["reload"],
+            Request::Submit(_) | Request::Job(_) => vec!["jobs"],
+            Request::History(_) | Request::Rerun(_) => vec!["history"],
+        }
     }
+}
+
+/// Turns the ANSI colours of the `Display` implementations on or off, e.g.
+/// when stdout isn't a terminal.
+pub fn set_colors(enabled: bool) {
+    COLORS.store(enabled, Ordering::Relaxed);
+}
 
-    Ok(ssh_success)
+fn color(code: &'static str) -> &'static str {
+    if COLORS.load(Ordering::Relaxed) {
+        code
+    } else {
+        ""
+    }
+}
+
+fn default_user() -> String {
+    "root".to_string()
+}
+
+fn default_port() -> u16 {
+    22
 }
 
 pub trait Message: Serialize {
@@ -91,10 +537,52 @@ pub trait Message: Serialize {
         Ok(bincode::deserialize(slice)?)
     }
 
+    /// Serializes the message into a complete frame, header included.
     fn encode(&self) -> Result<Vec<u8>, Error> {
-        let mut message = bincode::serialize(&self)?;
-        message.extend("\n".as_bytes());
-        Ok(message)
+        let payload = bincode::serialize(&self)?;
+        if payload.len() > MAX_FRAME_SIZE {
+            return Err(FrameError::TooLarge(payload.len()).into());
+        }
+
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+        frame.push(FRAME_MAGIC);
+        frame.push(FRAME_VERSION);
+        frame.extend(&(payload.len() as u32).to_be_bytes());
+        frame.extend(payload);
+        Ok(frame)
+    }
+
+    /// Reads the next frame from `reader` and decodes it. Returns `None` when
+    /// the peer closed the connection between two frames.
+    fn read_from<R: Read>(reader: &mut R) -> Result<Option<Self>, Error>
+    where
+        Self: Sized + DeserializeOwned,
+    {
+        let mut header = [0; FRAME_HEADER_SIZE];
+        loop {
+            match reader.read(&mut header[..1]) {
+                Ok(0) => return Ok(None),
+                Ok(_) => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        reader.read_exact(&mut header[1..])?;
+
+        if header[0] != FRAME_MAGIC {
+            return Err(FrameError::BadMagic(header[0]).into());
+        }
+        if header[1] != FRAME_VERSION {
+            return Err(FrameError::UnsupportedVersion(header[1]).into());
+        }
+        let length = u32::from_be_bytes([header[2], header[3], header[4], header[5]]) as usize;
+        if length > MAX_FRAME_SIZE {
+            return Err(FrameError::TooLarge(length).into());
+        }
+
+        let mut payload = vec![0; length];
+        reader.read_exact(&mut payload)?;
+        Self::decode(&payload).map(Some)
     }
 }
 
@@ -105,16 +593,35 @@ impl Message for Response {}
 pub struct ServerHandler<T> {
     pub stream: UnixStream,
     pub req: T,
+    /// Id of the request, in the logs and the audit log.
+    pub id: u64,
+    pub peer: Peer,
+    /// Where the handler records each node's outcome for the audit log.
+    pub trail: AuditTrail,
 }
 
 impl<T> ServerHandler<T> {
-    pub fn new(stream: UnixStream, req: T) -> ServerHandler<T> {
-        ServerHandler::<T> { stream, req }
+    pub fn new(
+        stream: UnixStream,
+        req: T,
+        id: u64,
+        peer: Peer,
+        trail: AuditTrail,
+    ) -> ServerHandler<T> {
+        ServerHandler::<T> {
+            stream,
+            req,
+            id,
+            peer,
+            trail,
+        }
     }
 }
 
 pub trait ServerActions<T> {
-    fn handle(self, server_config: &ServerConfig) -> Result<(), Error>;
+    /// Handles the request against `server_config`, the configuration it
+    /// was validated with, even if a reload happens in the meantime.
+    fn handle(self, server: &Server, server_config: &ServerConfig) -> Result<(), Error>;
 
     fn validate_request(&self, server_config: &ServerConfig) -> Result<(), Error>;
 }
@@ -138,13 +645,14 @@ impl Display for CmdReturn {
         match &self.data {
             SshReturn::SshSuccess(success) => {
                 if success.exit_status == 0 {
-                    write!(f, "{}", GREEN)?;
+                    write!(f, "{}", color(GREEN))?;
                     write!(f, "{} | SUCCESS:", self.node_name)?;
                 } else {
-                    write!(f, "{}", RED)?;
+                    write!(f, "{}", color(RED))?;
                     write!(f, "{} | FAILED:", self.node_name)?;
                 }
                 write!(f, "\n  exit_status: {}", success.exit_status)?;
+                write!(f, "\n  duration: {:.2}s", self.duration.as_secs_f64())?;
                 if let Some(stdout) = &success.stdout {
                     write!(f, "\n  stdout:\n")?;
                     for line in stdout.trim().lines() {
@@ -157,28 +665,365 @@ impl Display for CmdReturn {
                         writeln!(f, "    {}", line)?;
                     }
                 }
-                write!(f, "{}", NC)
+                write!(f, "{}", color(NC))
             }
             SshReturn::SshFailure(failure) => {
-                write!(f, "{}", RED)?;
+                write!(f, "{}", color(RED))?;
                 writeln!(f, "{} | TRANSPORT FAILURE:", self.node_name)?;
                 writeln!(f, "  {}", failure)?;
-                write!(f, "{}", NC)
+                write!(f, "{}", color(NC))
+            }
+            SshReturn::SshTimeout(stage, seconds) => {
+                write!(f, "{}", color(RED))?;
+                writeln!(f, "{} | TIMEOUT:", self.node_name)?;
+                writeln!(f, "  {} timed out after {}s", stage, seconds)?;
+                write!(f, "{}", color(NC))
+            }
+            SshReturn::SshSkipped(failure_ratio) => {
+                writeln!(f, "{} | SKIPPED:", self.node_name)?;
+                writeln!(
+                    f,
+                    "  rollout stopped after a batch with {:.0}% failures",
+                    failure_ratio * 100.0
+                )
+            }
+            SshReturn::SshAborted => {
+                write!(f, "{}", color(RED))?;
+                writeln!(f, "{} | ABORTED:", self.node_name)?;
+                writeln!(f, "  cancelled before the command finished")?;
+                write!(f, "{}", color(NC))
+            }
+        }
+    }
+}
+
+impl Display for CmdOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut lines = self.data.lines().peekable();
+        while let Some(line) = lines.next() {
+            write!(f, "{} | {}", self.node_name, line)?;
+            if lines.peek().is_some() {
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Auth::Agent => write!(f, "ssh agent"),
+            Auth::Key { key_file, .. } => write!(f, "private key {:?}", key_file),
+            Auth::Password { password_file } => write!(f, "password from {:?}", password_file),
+        }
+    }
+}
+
+impl Display for TimeoutStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimeoutStage::Connect => write!(f, "connect"),
+            TimeoutStage::Handshake => write!(f, "handshake"),
+            TimeoutStage::Exec => write!(f, "exec"),
+        }
+    }
+}
+
+impl Display for TransferReturn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.data {
+            TransferResult::Uploaded(size) => {
+                write!(f, "{}", color(GREEN))?;
+                write!(f, "{} | UPLOADED: {} bytes", self.node_name, size)?;
+            }
+            TransferResult::Downloaded { path, contents } => {
+                write!(f, "{}", color(GREEN))?;
+                write!(
+                    f,
+                    "{} | DOWNLOADED: {} bytes to {}",
+                    self.node_name,
+                    contents.len(),
+                    path
+                )?;
+            }
+            TransferResult::SshFailure(failure) => {
+                write!(f, "{}", color(RED))?;
+                writeln!(f, "{} | TRANSPORT FAILURE:", self.node_name)?;
+                write!(f, "  {}", failure)?;
+            }
+            TransferResult::SshTimeout(stage, seconds) => {
+                write!(f, "{}", color(RED))?;
+                writeln!(f, "{} | TIMEOUT:", self.node_name)?;
+                write!(f, "  {} timed out after {}s", stage, seconds)?;
+            }
+        }
+        write!(f, "{}", color(NC))
+    }
+}
+
+impl Display for ServerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Session pool:")?;
+        if self.pool.is_empty() {
+            write!(f, "  no session opened yet")?;
+        }
+        for (i, node) in self.pool.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(
+                f,
+                "  {} | idle: {}, opened: {}, reused: {}, discarded: {}",
+                node.node_name, node.idle_sessions, node.opened, node.reused, node.discarded
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for ReloadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Configuration reloaded: {} nodes, {} groups",
+            self.nodes, self.groups
+        )
+    }
+}
+
+impl Display for JobState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobState::Running => write!(f, "RUNNING"),
+            JobState::Finished => write!(f, "FINISHED"),
+            JobState::Cancelled => write!(f, "CANCELLED"),
+        }
+    }
+}
+
+impl Display for JobInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = match self.state {
+            JobState::Running => "",
+            JobState::Finished if self.failed == 0 => color(GREEN),
+            JobState::Finished | JobState::Cancelled => color(RED),
+        };
+        write!(f, "{}", code)?;
+        writeln!(f, "job {} | {}:", self.id, self.state)?;
+        writeln!(f, "  command: {}", self.command)?;
+        writeln!(
+            f,
+            "  nodes: {} ({}/{} done, {} failed)",
+            self.nodes.join(", "),
+            self.done,
+            self.nodes.len(),
+            self.failed
+        )?;
+        write!(f, "  submitted: {}", format_time(self.submitted_at))?;
+        if let Some(user) = &self.user {
+            write!(f, " by {}", user)?;
+        }
+        if let Some(finished_at) = self.finished_at {
+            write!(f, "\n  finished: {}", format_time(finished_at))?;
+        }
+        write!(f, "{}", if code.is_empty() { "" } else { color(NC) })
+    }
+}
+
+impl HistoryEntry {
+    /// Nodes where the command didn't succeed.
+    pub fn failed_nodes(&self) -> Vec<String> {
+        self.results
+            .iter()
+            .filter(|cmd_return| !cmd_return.data.is_success())
+            .map(|cmd_return| cmd_return.node_name.clone())
+            .collect()
+    }
+}
+
+/// One line per entry when listed, followed by every node result when shown
+/// with the alternate flag.
+impl Display for HistoryEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let failed = self.failed_nodes().len();
+        let code = if failed == 0 {
+            color(GREEN)
+        } else {
+            color(RED)
+        };
+        write!(
+            f,
+            "{}#{} {}{} | '{}' on {} nodes, {} failed",
+            code,
+            self.id,
+            format_time(self.started_at),
+            if self.job { " (job)" } else { "" },
+            self.command,
+            self.nodes.len(),
+            failed
+        )?;
+        if let Some(user) = &self.user {
+            write!(f, ", by {}", user)?;
+        }
+        write!(f, "{}", color(NC))?;
+
+        if f.alternate() {
+            for cmd_return in &self.results {
+                write!(f, "\n{}", cmd_return)?;
             }
         }
+        Ok(())
     }
 }
 
+/// Local time of `seconds` since the epoch.
+fn format_time(seconds: f64) -> String {
+    let time = UNIX_EPOCH + Duration::from_secs_f64(seconds.max(0.0));
+    chrono::DateTime::<chrono::Local>::from(time)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
 impl Display for ResponseError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", RED)?;
+        write!(f, "{}", color(RED))?;
         match &self {
             ResponseError::UnknownNodes(ukn_nodes) => write!(
                 f,
                 "ERROR: Unknown nodes or groups: [{}]",
                 ukn_nodes.join(", ")
             )?,
+            ResponseError::InvalidRequest(reason) => write!(f, "ERROR: Invalid request: {}", reason)?,
+            ResponseError::IncompatibleProtocol { client, server } => write!(
+                f,
+                "ERROR: Incompatible protocol versions: client speaks v{}-v{}, server speaks v{}-v{}",
+                client.0, client.1, server.0, server.1
+            )?,
+            ResponseError::InvalidConfig(reason) => write!(
+                f,
+                "ERROR: Configuration rejected, keeping the current one: {}",
+                reason
+            )?,
+            ResponseError::Forbidden(reason) => write!(f, "ERROR: Forbidden: {}", reason)?,
+            ResponseError::UnknownJob(id) => write!(f, "ERROR: Unknown job: {}", id)?,
+            ResponseError::UnknownHistoryEntry(id) => {
+                write!(f, "ERROR: Unknown history entry: {}", id)?
+            }
+        };
+        write!(f, "{}", color(NC))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn cmd_request(command: &str) -> Request {
+        Request::Cmd(CmdRequest {
+            nodes: vec!["web1".to_string()],
+            command: command.to_string(),
+            stream: false,
+            timeouts: Timeouts::default(),
+            parallelism: None,
+            batch: None,
+        })
+    }
+
+    #[test]
+    fn incompatible_protocol_keeps_its_variant_index() {
+        // Clients of any version must decode the answer to their handshake.
+        let err = ResponseError::IncompatibleProtocol {
+            client: (1, 1),
+            server: (1, 1),
         };
-        write!(f, "{}", NC)
+        assert_eq!(bincode::serialize(&err).unwrap()[..4], [1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn frame_round_trips_payload_with_newlines() {
+        // Newline delimited messages used to be cut at the first 0x0A.
+        let frame = cmd_request("printf 'a\\nb'\n\nuptime").encode().unwrap();
+        assert!(frame[FRAME_HEADER_SIZE..].contains(&b'\n'));
+
+        let mut reader = Cursor::new(frame);
+        match Request::read_from(&mut reader).unwrap() {
+            Some(Request::Cmd(cmd)) => assert_eq!(cmd.command, "printf 'a\\nb'\n\nuptime"),
+            other => panic!("unexpected message: {:?}", other),
+        }
+        assert!(Request::read_from(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn frames_are_read_back_to_back() {
+        let mut frames = cmd_request("first").encode().unwrap();
+        frames.extend(cmd_request("second").encode().unwrap());
+
+        let mut reader = Cursor::new(frames);
+        for expected in ["first", "second"] {
+            match Request::read_from(&mut reader).unwrap() {
+                Some(Request::Cmd(cmd)) => assert_eq!(cmd.command, expected),
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn bad_magic_is_rejected() {
+        let mut frame = cmd_request("uptime").encode().unwrap();
+        frame[0] = b'{';
+
+        match Request::read_from(&mut Cursor::new(frame)) {
+            Err(Error::Frame(FrameError::BadMagic(b'{'))) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unsupported_version_is_rejected() {
+        let mut frame = cmd_request("uptime").encode().unwrap();
+        frame[1] = FRAME_VERSION + 1;
+
+        match Request::read_from(&mut Cursor::new(frame)) {
+            Err(Error::Frame(FrameError::UnsupportedVersion(_))) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn oversized_frame_is_not_read() {
+        let mut header = vec![FRAME_MAGIC, FRAME_VERSION];
+        header.extend(&(MAX_FRAME_SIZE as u32 + 1).to_be_bytes());
+
+        match Request::read_from(&mut Cursor::new(header)) {
+            Err(Error::Frame(FrameError::TooLarge(size))) => assert_eq!(size, MAX_FRAME_SIZE + 1),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn oversized_message_is_not_encoded() {
+        let request = Request::Upload(UploadRequest {
+            nodes: vec!["web1".to_string()],
+            destination: "/tmp/big".to_string(),
+            contents: vec![0; MAX_FRAME_SIZE],
+            mode: 0o644,
+            owner: None,
+            timeouts: Timeouts::default(),
+        });
+
+        match request.encode() {
+            Err(Error::Frame(FrameError::TooLarge(size))) => assert!(size > MAX_FRAME_SIZE),
+            other => panic!("unexpected result: {:?}", other.map(|frame| frame.len())),
+        }
+    }
+
+    #[test]
+    fn truncated_frame_is_an_error() {
+        let mut frame = cmd_request("uptime").encode().unwrap();
+        frame.truncate(frame.len() - 1);
+
+        assert!(Request::read_from(&mut Cursor::new(frame)).is_err());
     }
 }
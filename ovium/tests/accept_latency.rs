@@ -0,0 +1,110 @@
+use ovium::client::Client;
+use ovium::types::{Request, StatusRequest};
+use std::fs;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Well under the 500ms the accept loop used to sleep between two polls.
+const MAX_LATENCY: Duration = Duration::from_millis(200);
+
+/// An `oviumd` serving a throwaway config, killed when dropped.
+struct Daemon {
+    child: Child,
+    dir: PathBuf,
+    socket_path: String,
+}
+
+impl Daemon {
+    fn start(name: &str) -> Daemon {
+        let dir = std::env::temp_dir().join(format!("ovium-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("nodes.toml"),
+            "[nodes]\nlocal = { ip = \"127.0.0.1\", port = 22 }\n",
+        )
+        .unwrap();
+        let socket_path = dir.join("ovium.sock").to_str().unwrap().to_string();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_oviumd"))
+            .args(["-s", &socket_path, "-c", dir.to_str().unwrap()])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let started = Instant::now();
+        while !Path::new(&socket_path).exists() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "oviumd didn't start"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        Daemon {
+            child,
+            dir,
+            socket_path,
+        }
+    }
+
+    fn status(&self) {
+        Client::new(&self.socket_path)
+            .run(Request::Status(StatusRequest {}), |_| {
+                Ok(ControlFlow::Continue(()))
+            })
+            .unwrap();
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[test]
+fn requests_are_accepted_right_away() {
+    let daemon = Daemon::start("accept");
+
+    for _ in 0..10 {
+        let started = Instant::now();
+        daemon.status();
+        let latency = started.elapsed();
+        assert!(latency < MAX_LATENCY, "request took {:?}", latency);
+    }
+}
+
+#[test]
+fn shutdown_is_prompt() {
+    let mut daemon = Daemon::start("shutdown");
+    daemon.status();
+
+    let started = Instant::now();
+    unsafe {
+        libc::kill(daemon.child.id() as libc::pid_t, libc::SIGTERM);
+    }
+    let exit_status = loop {
+        if let Some(exit_status) = daemon.child.try_wait().unwrap() {
+            break exit_status;
+        }
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "oviumd didn't stop"
+        );
+        thread::sleep(Duration::from_millis(5));
+    };
+
+    assert!(exit_status.success());
+    assert!(
+        started.elapsed() < MAX_LATENCY,
+        "shutdown took {:?}",
+        started.elapsed()
+    );
+    assert!(!Path::new(&daemon.socket_path).exists());
+}